@@ -3,23 +3,98 @@ use std::thread;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::ops::{Deref, DerefMut};
-use std::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
-use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, Instant};
 
 use irc::Config;
-use irc::server::Server;
+use irc::server::{Server, ServerState};
 use irc::client::Client;
-use irc::message::{Message, Command, Hostmask};
+use irc::stream::{self, ListenSocket, Stream};
+use chrono::Local;
+use openssl::base64;
+use rand::{self, Rng};
+
+use irc::message::{Message, MessageRef, Command, Hostmask, Ctcp, ModeChange};
+use irc::numeric;
 use irc::Connection;
 
 mod threadworker;
 
+// Reconnect delays double from the first up to the last, in seconds.
+const BACKOFF_MIN : u64 = 5;
+const BACKOFF_MAX : u64 = 300;
+// The most base64 an AUTHENTICATE line may carry.
+const SASL_CHUNK : usize = 400;
+// How often to ask with ISON whether our nick is free, in seconds.
+const ISON_INTERVAL : u64 = 60;
+// How long services get to confirm our login before clients hear about it, in seconds.
+const IDENTIFY_TIMEOUT : u64 = 30;
+// At most this many CTCP replies per network, and one per sender, per window.
+const CTCP_BURST : usize = 4;
+const CTCP_WINDOW : u64 = 10;
+
 #[derive(Clone, Debug)]
 pub enum Event {
-    ServerRead(usize, Message),
+    ServerRead(usize, String),
+    ServerDisconnect(usize),
+    ServerConnected(usize, Arc<Mutex<Stream>>),
+    ServerConnectFailed(usize, String),
+    Reconnect(usize),
+    PingServer(usize),
+    CheckNick(usize),
+    IdentifyTimeout(usize),
     ClientRead(usize, Message),
-    AcceptConn(Arc<Mutex<TcpStream>>),
+    AcceptConn(Arc<Mutex<Stream>>),
+}
+
+/// Certificate and key for the client listener, in PEM.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert : String,
+    pub key : String,
+    pub generate : bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(String),
+    /// A socket path and the permissions to give it.
+    Unix(String, Option<u32>),
+}
+
+/// One place clients can connect to, optionally over TLS.
+#[derive(Clone, Debug)]
+pub struct ListenConfig {
+    pub addr : ListenAddr,
+    pub tls : bool,
+}
+
+/// Recent CTCP replies on one network, so that a flood can't get us killed
+/// for sending too much.
+#[derive(Default)]
+struct CtcpLimit {
+    replies : VecDeque<Instant>,
+    senders : HashMap<String, Instant>,
+}
+
+impl CtcpLimit {
+    fn allow(&mut self, sender: String) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(CTCP_WINDOW);
+        while self.replies.front().is_some_and(|t| now.duration_since(*t) >= window) {
+            self.replies.pop_front();
+        }
+        self.senders.retain(|_, t| now.duration_since(*t) < window);
+        if self.replies.len() >= CTCP_BURST || self.senders.contains_key(&sender) {
+            return false;
+        }
+        self.replies.push_back(now);
+        self.senders.insert(sender, now);
+        true
+    }
 }
 
 pub struct Bouncer {
@@ -29,35 +104,120 @@ pub struct Bouncer {
     names : HashMap<String, usize>,
     clients : Vec<Client>,
     srvsendtxs : Vec<Sender<String>>,
+    backoffs : Vec<u32>,
+    nick_attempts : Vec<usize>,
+    awaiting_pong : Vec<bool>,
+    ison_timers : Vec<bool>,
+    cap_reqs : Vec<usize>,
+    identified : Vec<bool>,
+    held_joins : Vec<bool>,
+    halted : Vec<bool>,
+    sasl_success : Vec<bool>,
+    pending_keys : Vec<HashMap<String, String>>,
+    ctcp_limits : Vec<CtcpLimit>,
+    pending : Vec<Vec<String>>,
+    rejoins : HashMap<usize, Vec<(String, Option<String>)>>,
+    pinfile : String,
     clntsendtxs : Vec<Sender<String>>,
     eventrx : Receiver<Event>,
     eventtx : Sender<Event>,
 }
 
 impl Bouncer {
-    pub fn new(cfgs: Vec<Config>) -> io::Result<Bouncer> {
+    pub fn new(mut cfgs: Vec<Config>, listens: Vec<ListenConfig>, tls: Option<TlsConfig>, pinfile: String) -> io::Result<Bouncer> {
         let (eventtx, eventrx) = channel();
-        match TcpListener::bind("0.0.0.0:6677") {
-            Ok(listener) => {
-                let listeneventtx = eventtx.clone();
-                thread::Builder::new().name("LISTEN".to_string()).spawn(move || {
-                    threadworker::Listener::new(listener, listeneventtx).work();
-                });
-                Ok(Bouncer { hostmask: Hostmask::Server("carbon.fron.io".to_string()), configs: cfgs, srvsendtxs: Vec::new(), clntsendtxs: Vec::new(), servers: Vec::new(), clients: Vec::new(), names: HashMap::new(), eventrx: eventrx, eventtx: eventtx })
-            },
-            Err(err) => Err(err)
+        for (name, fingerprint) in load_pins(pinfile.as_str()) {
+            if let Some(cfg) = cfgs.iter_mut().find(|c| c.name == name && c.tofu && c.fingerprints.is_empty()) {
+                println!("[{}] Using pinned certificate fingerprint {}", name, fingerprint);
+                cfg.fingerprints.push(fingerprint);
+            }
         }
+        let acceptor = match tls {
+            Some(tls) => Some(stream::acceptor(tls.cert.as_str(), tls.key.as_str(), tls.generate)?),
+            None => None
+        };
+        let listens = if listens.is_empty() {
+            vec![ListenConfig { addr: ListenAddr::Tcp("0.0.0.0:6677".to_string()), tls: acceptor.is_some() }]
+        } else {
+            listens
+        };
+        for listen in listens {
+            let (listener, name) = match listen.addr {
+                ListenAddr::Tcp(ref address) => (ListenSocket::bind_tcp(address)?, address.clone()),
+                ListenAddr::Unix(ref path, mode) => (ListenSocket::bind_unix(path, mode)?, path.clone())
+            };
+            let listenacceptor = match (listen.tls, acceptor.as_ref()) {
+                (false, _) => None,
+                (true, Some(acceptor)) => Some(acceptor.clone()),
+                (true, None) => {
+                    let text = format!("Listener {} wants TLS but no tls certificate is configured", name);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, text));
+                }
+            };
+            println!("Listening on {}{}", name, if listen.tls { " (TLS)" } else { "" });
+            let listeneventtx = eventtx.clone();
+            thread::Builder::new().name(format!("LISTEN-{}", name)).spawn(move || {
+                threadworker::Listener::new(listener, listenacceptor, listeneventtx).work();
+            });
+        }
+        Ok(Bouncer { hostmask: Hostmask::Server("carbon.fron.io".to_string()), configs: cfgs, srvsendtxs: Vec::new(), backoffs: Vec::new(), nick_attempts: Vec::new(), awaiting_pong: Vec::new(), ison_timers: Vec::new(), cap_reqs: Vec::new(), identified: Vec::new(), held_joins: Vec::new(), halted: Vec::new(), sasl_success: Vec::new(), pending_keys: Vec::new(), ctcp_limits: Vec::new(), pending: Vec::new(), rejoins: HashMap::new(), pinfile: pinfile, clntsendtxs: Vec::new(), servers: Vec::new(), clients: Vec::new(), names: HashMap::new(), eventrx: eventrx, eventtx: eventtx })
     }
 
     pub fn run(mut self) {
         for (i, cfg) in self.configs.clone().iter().enumerate() {
-            self.start_server(i, cfg.clone());
+            // Until the first connection is up, lines for it go nowhere.
+            let (sendtx, _) = channel();
+            self.srvsendtxs.push(sendtx);
+            self.backoffs.push(0);
+            self.nick_attempts.push(0);
+            self.awaiting_pong.push(false);
+            self.ison_timers.push(false);
+            self.cap_reqs.push(0);
+            self.identified.push(false);
+            self.held_joins.push(false);
+            self.halted.push(false);
+            self.sasl_success.push(false);
+            self.pending_keys.push(HashMap::new());
+            self.ctcp_limits.push(CtcpLimit::default());
+            self.pending.push(Vec::new());
+            self.names.insert(cfg.name.clone(), i);
+            self.servers.push(Server::new(i, cfg.clone()));
+            self.start_server(i);
         }
         loop {
             let msgresult = self.eventrx.recv().clone();
             match msgresult {
-                Ok(Event::ServerRead(id, msg)) => {
-                    self.handlesrv(id, msg);
+                Ok(Event::ServerRead(id, line)) => {
+                    self.handlesrv(id, line.as_str());
+                }
+                Ok(Event::ServerDisconnect(id)) => {
+                    self.server_lost(id);
+                }
+                Ok(Event::ServerConnected(id, rc)) => {
+                    match rc.lock().unwrap().try_clone() {
+                        Ok(stream) => {
+                            self.server_connected(id, stream);
+                        }
+                        Err(err) => {
+                            self.connect_failed(id, err.to_string());
+                        }
+                    }
+                }
+                Ok(Event::ServerConnectFailed(id, err)) => {
+                    self.connect_failed(id, err);
+                }
+                Ok(Event::Reconnect(id)) => {
+                    self.start_server(id);
+                }
+                Ok(Event::PingServer(id)) => {
+                    self.ping_server(id);
+                }
+                Ok(Event::IdentifyTimeout(id)) => {
+                    self.identify_timeout(id);
+                }
+                Ok(Event::CheckNick(id)) => {
+                    self.ison_timers[id] = false;
+                    self.check_nick(id);
                 }
                 Ok(Event::ClientRead(id, msg)) => {
                     self.handleclnt(id, msg);
@@ -79,11 +239,11 @@ impl Bouncer {
         }
     }
 
-    fn start_client(&mut self, stream: TcpStream) {
+    fn start_client(&mut self, stream: Stream) {
         let (sendtx, sendrx) = channel();
         self.clntsendtxs.push(sendtx.clone());
         let readeventtx = self.eventtx.clone();
-        let clientname = format!("{}", stream.peer_addr().unwrap());
+        let clientname = stream.peer_name();
         let client = Client::from_stream(self.clients.len(), stream);
         match client.try_clone() {
             Ok(client_clone) => {
@@ -121,145 +281,545 @@ impl Bouncer {
         self.clients.push(client);
     }
 
-    fn start_server(&mut self, id: usize, cfg: Config) {
+    /// Connects on a thread of its own, since a network that drops our packets
+    /// could otherwise stall every other one until the connect times out.
+    fn start_server(&mut self, id: usize) {
+        let name = self.configs[id].name.clone();
+        let config = self.configs[id].clone();
+        let eventtx = self.eventtx.clone();
+        self.servers[id].set_state(ServerState::Connecting);
+        let connectthreadname = format!("{}-CONNECT", name);
+        let spawned = thread::Builder::new().name(connectthreadname).spawn(move || {
+            let event = match Stream::connect(&config) {
+                Ok(stream) => Event::ServerConnected(id, Arc::new(Mutex::new(stream))),
+                Err(err) => Event::ServerConnectFailed(id, err.to_string()),
+            };
+            let _ = eventtx.send(event);
+        });
+        if let Err(err) = spawned {
+            self.connect_failed(id, err.to_string());
+        }
+    }
+
+    fn connect_failed(&mut self, id: usize, err: String) {
+        let text = format!("Could not connect to {}: {}", self.configs[id].name, err);
+        println!("{}", text);
+        self.servers[id].set_state(ServerState::Disconnected);
+        self.notify_clients(text.as_str());
+        self.schedule_reconnect(id);
+    }
+
+    fn server_connected(&mut self, id: usize, stream: Stream) {
+        let name = self.configs[id].name.clone();
+        let config = self.configs[id].clone();
+        self.servers[id].attach(&config, stream);
+        // Trust on first use: pin whatever certificate we see first, so that
+        // reconnects to this network, and later runs, must present the same one.
+        if self.configs[id].tofu && self.configs[id].fingerprints.is_empty() {
+            if let Some(fingerprint) = self.servers[id].fingerprint() {
+                println!("[{}] Pinned certificate fingerprint {}", name, fingerprint);
+                if let Err(err) = save_pin(self.pinfile.as_str(), name.as_str(), fingerprint.as_str()) {
+                    println!("[{}] Could not save the pin to {}, it will be trusted anew next run: {}", name, self.pinfile, err);
+                }
+                self.configs[id].fingerprints.push(fingerprint);
+            }
+        }
         let (sendtx, sendrx) = channel();
-        self.srvsendtxs.push(sendtx.clone());
         let readtx = self.eventtx.clone();
-		if let Ok(server) = Server::connect(id, cfg) {
-            if let Ok(server_clone) = server.try_clone() {
-                let readthreadname = format!("{}-IN", server.name());
-                let readthread = thread::Builder::new().name(readthreadname).spawn(move || {
-                    threadworker::ReadWorker::<Server>::new(server_clone, readtx).work();
-                });
-            } else {
-                println!("Error starting read thread");
-            }
-            if let Ok(server_clone) = server.try_clone() {
-                let sendthreadname = format!("{}-OUT", server.name());
-                let sendthread = thread::Builder::new().name(sendthreadname).spawn(move || {
-                    sendtx.send(Message::user(Hostmask::None, server_clone.config.nick.as_str(), "carbon").to_string());
-                    sendtx.send(Message::nick(Hostmask::None, server_clone.config.nick.as_str()).to_string());
-                    match server_clone.config.pass.as_str() {
-                        "" => (),
-                        pass => {
-                            sendtx.send(Message::pass(Hostmask::None, pass).to_string());
-                        }
-                    }
-                    threadworker::SendWorker::new(server_clone, sendrx).work();
-                });
-            } else {
-                println!("Error starting send thread");
-            }
-            self.names.insert(server.name(), self.servers.len());
-            self.servers.push(server);
+        if let Ok(server_clone) = self.servers[id].try_clone() {
+            let readthreadname = format!("{}-IN", name);
+            let readthread = thread::Builder::new().name(readthreadname).spawn(move || {
+                threadworker::ReadWorker::<Server>::new(server_clone, readtx).work();
+            });
+        } else {
+            println!("Error starting read thread");
+        }
+        if let Ok(server_clone) = self.servers[id].try_clone() {
+            let sendthreadname = format!("{}-OUT", name);
+            let sendthread = thread::Builder::new().name(sendthreadname).spawn(move || {
+                threadworker::SendWorker::new(server_clone, sendrx).work();
+            });
         } else {
-            println!("Error connecting to IRC");
+            println!("Error starting send thread");
         }
+        // Pinging starts right away, so a server that goes quiet while we
+        // register doesn't leave us waiting forever either.
+        if let Ok(server_clone) = self.servers[id].try_clone() {
+            let pingtx = self.eventtx.clone();
+            let pingthreadname = format!("{}-PING", name);
+            let pingthread = thread::Builder::new().name(pingthreadname).spawn(move || {
+                threadworker::PingTimer::new(server_clone, pingtx).work();
+            });
+        } else {
+            println!("Error starting ping thread");
+        }
+        // Replacing the sender lets the last connection's send worker finish.
+        self.srvsendtxs[id] = sendtx;
+        self.nick_attempts[id] = 0;
+        self.awaiting_pong[id] = false;
+        self.cap_reqs[id] = 0;
+        self.identified[id] = false;
+        self.held_joins[id] = false;
+        self.sasl_success[id] = false;
+        self.pending_keys[id].clear();
+        // PASS has to come first or servers ignore it. Registration is then
+        // held until CAP END, so we can ask for capabilities first.
+        self.servers[id].set_state(ServerState::CapNegotiating);
+        if !config.pass.is_empty() {
+            self.send_srv(id, Message::pass(Hostmask::None, config.pass.as_str()).to_string());
+        }
+        self.send_srv(id, Message::cap(Hostmask::None, None, "LS", &["302"]).to_string());
+        self.send_srv(id, Message::nick(Hostmask::None, config.nick.as_str()).to_string());
+        self.send_srv(id, Message::user(Hostmask::None, config.username.as_str(), config.realname.as_str()).to_string());
     }
 
-    fn handlesrv(&mut self, id: usize, msg: Message) {
-        match msg.command {
-            Command::RPL_WELCOME(ref params) => {
-                if let Ok(server) = self.servers[id].try_clone() {
-                    let pingthreadname = format!("{}-PING", server.name());
-                    let pingthread = thread::Builder::new().name(pingthreadname).spawn(move || {
-                        threadworker::PingWorker::new(server).work();
-                    });
-                } else {
-                    println!("Error starting ping thread");
-                    self.servers[id].set_connected(false);
-                }
+    /// Handles a connection that went away: remembers its channels for when we
+    /// are back, tells the clients in them and schedules a reconnect, unless
+    /// the network refused our login.
+    fn server_lost(&mut self, id: usize) {
+        self.servers[id].disconnect();
+        if !self.pending[id].is_empty() {
+            println!("[{}] Dropping {} queued lines", self.servers[id].name(), self.pending[id].len());
+            self.pending[id].clear();
+        }
+        let chans : Vec<(String, Option<String>)> = self.servers[id].channels().into_iter()
+            .map(|c| (c.name, c.key))
+            .collect();
+        // A connection that dropped before rejoining anything still owes the old list.
+        if !chans.is_empty() {
+            self.rejoins.insert(id, chans);
+        }
+        let text = if self.halted[id] {
+            self.servers[id].set_state(ServerState::Disconnected);
+            format!("Disconnected from {}, not reconnecting until restarted", self.servers[id].name())
+        } else {
+            let delay = self.schedule_reconnect(id);
+            format!("Disconnected from {}, reconnecting in {}s", self.servers[id].name(), delay.as_secs())
+        };
+        println!("{}", text);
+        if let Some(chans) = self.rejoins.get(&id) {
+            for (chan, _) in chans {
+                let rawchan = self.rawchan(id, chan);
+                self.relay(id, rawchan.as_str(), Message::notice(self.hostmask.clone(), rawchan.as_str(), text.as_str()));
+            }
+        }
+    }
 
-                let nick = params.split(' ').next().unwrap();
-                self.servers[id].set_nick(nick.to_string());
+    /// Reconnects after a jittered, exponentially growing delay.
+    fn schedule_reconnect(&mut self, id: usize) -> Duration {
+        let attempts = self.backoffs[id].min(16);
+        self.backoffs[id] += 1;
+        self.servers[id].set_state(ServerState::Backoff);
+        let ceiling = (BACKOFF_MIN << attempts).min(BACKOFF_MAX) as f64;
+        // Somewhere in the upper half, so networks that dropped together spread out.
+        let delay = Duration::from_millis((ceiling * (0.5 + rand::thread_rng().gen::<f64>() / 2.0) * 1000.0) as u64);
+        let eventtx = self.eventtx.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let _ = eventtx.send(Event::Reconnect(id));
+        });
+        delay
+    }
 
-                for chan in self.servers[id].config.chans.iter() {
-                    self.send_srv(id, Message::join(Hostmask::None, chan.as_str()).to_string())
-                }
-            }
-            Command::PING(ref param) => {
-                self.send_srv(id, Message::pong(Hostmask::None, param).to_string());
+    /// Pings a network, or drops it if the last ping was never answered or we
+    /// still aren't registered. The read worker then reports the connection
+    /// gone as usual.
+    fn ping_server(&mut self, id: usize) {
+        if !self.servers[id].is_connected() {
+            return;
+        }
+        if self.servers[id].state() != ServerState::Registered {
+            println!("[{}] Registration timed out", self.servers[id].name());
+            self.servers[id].disconnect();
+        } else if self.awaiting_pong[id] {
+            println!("[{}] Ping timeout", self.servers[id].name());
+            self.servers[id].disconnect();
+        } else {
+            self.awaiting_pong[id] = true;
+            self.send_srv(id, Message::ping(Hostmask::None, "carbon").to_string());
+        }
+    }
+
+    fn handlesrv(&mut self, id: usize, line: &str) {
+        // Whatever was still queued from a connection we dropped is stale.
+        if !self.servers[id].is_connected() {
+            return;
+        }
+        let msg = MessageRef::parse(line);
+        if let Some(code) = msg.numeric() {
+            self.handlesrv_numeric(id, code, &msg);
+            return;
+        }
+        match &*msg.verb() {
+            "PING" => {
+                self.send_srv(id, Message::pong(Hostmask::None, msg.param(0)).to_string());
             }
-            Command::PONG(ref param) => {
-                self.servers[id].register_pong();
+            "PONG" => {
+                self.awaiting_pong[id] = false;
             }
-            Command::JOIN(ref chan) => {
-                match msg.hostmask {
-                    Hostmask::User(ref nick, _, _) => {
-                        if *nick == self.servers[id].get_nick() {
-                            self.servers[id].add_channel(chan);
+            "JOIN" => {
+                let chan = msg.param(0);
+                if let Some(nick) = msg.nick() {
+                    if self.servers[id].is_me(nick) {
+                        // Our own JOIN tells us how the server sees us.
+                        if let Hostmask::User(_, user, host) = msg.hostmask() {
+                            self.servers[id].set_userdata(user, host);
                         }
-                        else {
-                            for client in &self.clients {
-                                let rawchan = "#".to_string()+self.servers[id].name().as_str()+chan;
-                                if client.has_channel(rawchan.as_str()) {
-                                    self.send_clnt(client.id, Message::join(msg.hostmask.clone(), rawchan.as_str()).to_string());
-                                }
-                            }
+                        self.servers[id].add_channel(chan);
+                        self.servers[id].add_member(chan, nick);
+                        // Keep the key we joined with, so a rejoin after a reconnect has it.
+                        let folded = self.servers[id].casemapping().fold(chan);
+                        if let Some(key) = self.pending_keys[id].remove(&folded) {
+                            self.servers[id].set_key(chan, Some(key));
                         }
                     }
-                    Hostmask::Server(_) => (),
-                    Hostmask::None => ()
+                    else {
+                        self.servers[id].add_member(chan, nick);
+                        let rawchan = self.rawchan(id, chan);
+                        self.relay(id, rawchan.as_str(), Message::join(msg.hostmask(), rawchan.as_str()));
+                    }
                 }
             }
-            Command::PART(ref chan, ref message) => {
-                match msg.hostmask {
-                    Hostmask::User(ref nick, _, _) => {
-                        if *nick == self.servers[id].get_nick() {
-                            self.servers[id].remove_channel(chan);
-                        }
-                        else {
-                            for client in &self.clients {
-                                let rawchan = "#".to_string()+self.servers[id].name().as_str()+chan;
-                                if client.has_channel(rawchan.as_str()) {
-                                    self.send_clnt(client.id, Message::part(msg.hostmask.clone(), rawchan.as_str(), message).to_string());
-                                }
-                            }
-                        }
+            "PART" => {
+                let chan = msg.param(0);
+                if let Some(nick) = msg.nick() {
+                    if self.servers[id].is_me(nick) {
+                        self.servers[id].remove_channel(chan);
+                    }
+                    else {
+                        self.servers[id].remove_member(chan, nick);
+                        let rawchan = self.rawchan(id, chan);
+                        self.relay(id, rawchan.as_str(), Message::part(msg.hostmask(), rawchan.as_str(), msg.param(1)));
                     }
-                    Hostmask::Server(_) => (),
-                    Hostmask::None => ()
                 }
             }
-            Command::QUIT(ref chan, ref message) => {
-                match msg.hostmask {
-                    Hostmask::User(ref nick, _, _) => {
-                        if *nick == self.servers[id].get_nick() {
-                            /* handle quit */
-                        }
-                        else {
-                            for client in &self.clients {
-                                let rawchan = "#".to_string()+self.servers[id].name().as_str()+chan;
-                                if client.has_channel(rawchan.as_str()) {
-                                    self.send_clnt(client.id, Message::quit(msg.hostmask.clone(), rawchan.as_str(), message).to_string());
-                                }
-                            }
-                        }
+            "CAP" => {
+                self.handlesrv_cap(id, &msg);
+            }
+            "AUTHENTICATE" if msg.param(0) == "+" => {
+                self.authenticate(id);
+            }
+            "NICK" => {
+                let new = msg.param(0);
+                if let Some(nick) = msg.nick() {
+                    if self.servers[id].is_me(nick) {
+                        self.servers[id].set_nick(new.to_string());
+                        self.servers[id].rename_member(nick, new);
+                        self.nick_changed(id);
+                    }
+                    else {
+                        let chans = self.servers[id].rename_member(nick, new);
+                        self.relay_shared(id, &chans, Message::nick(msg.hostmask(), new));
                     }
-                    Hostmask::Server(_) => (),
-                    Hostmask::None => ()
                 }
             }
-            Command::PRIVMSG(ref chan, ref message) => {
-                for client in &self.clients {
-                    let rawchan = "#".to_string()+self.servers[id].name().as_str()+chan;
-                    if client.has_channel(rawchan.as_str()) {
-                        self.send_clnt(client.id, Message::privmsg(msg.hostmask.clone(), rawchan.as_str(), message).to_string());
+            "QUIT" => {
+                if let Some(nick) = msg.nick() {
+                    if self.servers[id].is_me(nick) {
+                        /* handle quit */
+                    }
+                    else {
+                        let chans = self.servers[id].remove_member_everywhere(nick);
+                        self.relay_shared(id, &chans, Message::quit(msg.hostmask(), msg.param(0)));
                     }
                 }
             }
-            Command::NOTICE(ref chan, ref message) => {
-                for client in &self.clients {
-                    let rawchan = "#".to_string()+self.servers[id].name().as_str()+chan;
-                    if client.has_channel(rawchan.as_str()) {
-                        self.send_clnt(client.id, Message::notice(msg.hostmask.clone(), rawchan.as_str(), message).to_string());
+            "MODE" => {
+                let target = msg.param(0);
+                if self.servers[id].is_channel(target) {
+                    let modes = &msg.params[1..];
+                    let changes = self.servers[id].with_isupport(|isupport| ModeChange::parse(modes, isupport));
+                    self.servers[id].apply_modes(target, &changes);
+                    let rawchan = self.rawchan(id, target);
+                    self.relay(id, rawchan.as_str(), Message::mode(msg.hostmask(), rawchan.as_str(), modes));
+                }
+            }
+            "PRIVMSG" => {
+                // Queries sent to a whole channel are left for the people in it.
+                if let Some(ctcp) = msg.get_ctcp() {
+                    if !ctcp.is_action() && !self.servers[id].is_channel(msg.param(0)) &&
+                        !self.clients.iter().any(|c| c.is_connected()) {
+                        self.answer_ctcp(id, &msg.hostmask(), &ctcp);
                     }
                 }
+                let rawchan = self.rawchan(id, msg.param(0));
+                self.relay(id, rawchan.as_str(), Message::privmsg(msg.hostmask(), rawchan.as_str(), msg.param(1)));
+            }
+            "NOTICE" => {
+                self.nickserv_notice(id, &msg);
+                let rawchan = self.rawchan(id, msg.param(0));
+                self.relay(id, rawchan.as_str(), Message::notice(msg.hostmask(), rawchan.as_str(), msg.param(1)));
+            }
+            _ => ()
+        }
+    }
+
+    /// Negotiates capabilities: collects what the server offers, asks for the
+    /// ones we want and ends negotiation once every request is answered.
+    fn handlesrv_cap(&mut self, id: usize, msg: &MessageRef) {
+        let subcommand = msg.param(1).to_uppercase();
+        // Long lists arrive over several lines, all but the last marked with '*'.
+        let more = msg.params.len() > 3 && msg.param(2) == "*";
+        let list = msg.params.last().cloned().unwrap_or("");
+        match subcommand.as_str() {
+            "LS" => {
+                self.servers[id].add_caps(list);
+                if !more && self.servers[id].state() == ServerState::CapNegotiating {
+                    self.request_caps(id);
+                    self.end_cap(id);
+                }
+            }
+            "NEW" => {
+                self.servers[id].add_caps(list);
+                self.request_caps(id);
+            }
+            "DEL" => {
+                self.servers[id].remove_caps(list);
+            }
+            "ACK" | "NAK" => {
+                if subcommand == "ACK" {
+                    self.servers[id].ack_caps(list);
+                } else {
+                    println!("[{}] Server refused capabilities {}", self.servers[id].name(), list);
+                }
+                self.cap_reqs[id] = self.cap_reqs[id].saturating_sub(1);
+                self.end_cap(id);
             }
             _ => ()
         }
     }
 
+    /// Asks for the wanted capabilities the server offers that are not yet
+    /// enabled, in as many REQs as it takes to fit them on lines.
+    fn request_caps(&mut self, id: usize) {
+        let caps = self.servers[id].caps();
+        // SASL is wanted whenever credentials are configured.
+        let sasl = self.configs[id].sasl.as_ref().map(|_| "sasl");
+        let wanted : Vec<&str> = self.configs[id].caps.iter()
+            .map(|c| c.as_str())
+            .chain(sasl)
+            .filter(|c| caps.available.contains_key(*c) && !caps.enabled.contains(*c))
+            .collect();
+        let mut line = String::new();
+        for cap in wanted {
+            if !line.is_empty() && line.len() + cap.len() > 400 {
+                self.send_srv(id, Message::cap(Hostmask::None, None, "REQ", &[line.as_str()]).to_string());
+                self.cap_reqs[id] += 1;
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(cap);
+        }
+        if !line.is_empty() {
+            self.send_srv(id, Message::cap(Hostmask::None, None, "REQ", &[line.as_str()]).to_string());
+            self.cap_reqs[id] += 1;
+        }
+    }
+
+    /// Ends negotiation during registration once no requests are outstanding,
+    /// logging in with SASL first when it is configured and was enabled.
+    fn end_cap(&mut self, id: usize) {
+        if self.cap_reqs[id] != 0 || self.servers[id].state() != ServerState::CapNegotiating {
+            return;
+        }
+        match self.configs[id].sasl {
+            Some(ref sasl) if self.servers[id].has_cap("sasl") => {
+                let mechanism = sasl.mechanism.to_uppercase();
+                self.servers[id].set_state(ServerState::Authenticating);
+                self.send_srv(id, Message::authenticate(Hostmask::None, mechanism.as_str()).to_string());
+            }
+            // Registering anyway would show us unauthenticated, which is what requiring SASL prevents.
+            Some(ref sasl) if sasl.required => {
+                let text = format!("{} did not offer SASL, disconnecting", self.servers[id].name());
+                println!("{}", text);
+                self.notify_clients(text.as_str());
+                self.servers[id].disconnect();
+            }
+            _ => self.finish_cap(id)
+        }
+    }
+
+    fn finish_cap(&mut self, id: usize) {
+        self.send_srv(id, Message::cap(Hostmask::None, None, "END", &[]).to_string());
+        self.servers[id].set_state(ServerState::Registering);
+    }
+
+    /// Answers the server's go-ahead with our credentials, base64 encoded and
+    /// split into chunks. A final full chunk is followed by an empty one.
+    /// EXTERNAL sends nothing, as the client certificate identifies us.
+    fn authenticate(&mut self, id: usize) {
+        let payload = match self.configs[id].sasl {
+            Some(ref sasl) if sasl.mechanism == "external" => {
+                self.send_srv(id, Message::authenticate(Hostmask::None, "+").to_string());
+                return;
+            }
+            Some(ref sasl) => format!("{}\0{}\0{}", sasl.user, sasl.user, sasl.password),
+            None => return
+        };
+        let encoded = base64::encode_block(payload.as_bytes());
+        for chunk in encoded.as_bytes().chunks(SASL_CHUNK) {
+            self.send_srv(id, Message::authenticate(Hostmask::None, &String::from_utf8_lossy(chunk)).to_string());
+        }
+        if encoded.len().is_multiple_of(SASL_CHUNK) {
+            self.send_srv(id, Message::authenticate(Hostmask::None, "+").to_string());
+        }
+    }
+
+    /// Finishes SASL either way, given the numeric that ended it. A failed
+    /// login ends the connection if the network requires it, and otherwise
+    /// registration carries on without it. Credentials the server refused
+    /// would only be refused again, and retrying them can get the account
+    /// locked, so then we don't reconnect either.
+    fn sasl_done(&mut self, id: usize, code: u16) {
+        if self.servers[id].state() != ServerState::Authenticating {
+            return;
+        }
+        let success = code == numeric::RPL_SASLSUCCESS || code == numeric::ERR_SASLALREADY;
+        self.sasl_success[id] = success;
+        let required = self.configs[id].sasl.as_ref().is_some_and(|s| s.required);
+        if !success && required {
+            self.halted[id] = code == numeric::ERR_SASLFAIL || code == numeric::ERR_NICKLOCKED;
+            let text = format!("SASL authentication to {} failed, disconnecting", self.servers[id].name());
+            println!("{}", text);
+            self.notify_clients(text.as_str());
+            self.servers[id].disconnect();
+            return;
+        }
+        if !success {
+            println!("[{}] SASL authentication failed, continuing without it", self.servers[id].name());
+        }
+        self.finish_cap(id);
+    }
+
+    fn handlesrv_numeric(&mut self, id: usize, code: u16, msg: &MessageRef) {
+        let params = &msg.params;
+        match code {
+            numeric::RPL_WELCOME => {
+                // Servers that don't speak CAP register us without ever getting to SASL.
+                let required = self.configs[id].sasl.as_ref().is_some_and(|s| s.required);
+                if required && !self.sasl_success[id] {
+                    let text = format!("{} registered us without SASL, disconnecting", self.servers[id].name());
+                    println!("{}", text);
+                    self.notify_clients(text.as_str());
+                    self.servers[id].disconnect();
+                    return;
+                }
+                if let Some(nick) = params.first() {
+                    self.servers[id].set_nick(nick.to_string());
+                }
+                self.backoffs[id] = 0;
+                self.servers[id].set_state(ServerState::Registered);
+                for line in std::mem::take(&mut self.pending[id]) {
+                    self.send_srv(id, line);
+                }
+                self.identify(id);
+            }
+            // The first parameter is our nick and the last one is free text.
+            numeric::RPL_ISUPPORT if params.len() > 2 => {
+                let tokens : Vec<String> = params[1..params.len() - 1].iter().map(|t| t.to_string()).collect();
+                self.servers[id].update_isupport(&tokens);
+            }
+            // Our nick was refused before we got in, so move on to the next candidate.
+            numeric::ERR_NICKNAMEINUSE | numeric::ERR_ERRONEUSNICKNAME | numeric::ERR_UNAVAILRESOURCE
+                if self.servers[id].state() != ServerState::Registered => {
+                self.nick_attempts[id] += 1;
+                match self.configs[id].nick_candidate(self.nick_attempts[id]) {
+                    Some(nick) => {
+                        println!("[{}] Nick {} is unavailable, trying {}", self.servers[id].name(), params.get(1).unwrap_or(&""), nick);
+                        self.send_srv(id, Message::nick(Hostmask::None, nick.as_str()).to_string());
+                    }
+                    // Retrying at line speed would only get us killed, so wait for the reconnect.
+                    None => {
+                        let text = format!("No nick we tried was accepted on {}, disconnecting", self.servers[id].name());
+                        println!("{}", text);
+                        self.notify_clients(text.as_str());
+                        self.servers[id].disconnect();
+                    }
+                }
+            }
+            numeric::RPL_LOGGEDIN if params.len() > 2 => {
+                println!("[{}] Logged in as {}", self.servers[id].name(), params[2]);
+                self.mark_identified(id);
+            }
+            numeric::RPL_LOGGEDOUT => {
+                self.identified[id] = false;
+            }
+            // A hidden host is what holding joins protects, so it counts too.
+            numeric::RPL_HOSTHIDDEN => {
+                self.mark_identified(id);
+            }
+            numeric::RPL_SASLSUCCESS | numeric::ERR_SASLALREADY => {
+                self.sasl_done(id, code);
+            }
+            numeric::ERR_NICKLOCKED | numeric::ERR_SASLFAIL | numeric::ERR_SASLTOOLONG | numeric::ERR_SASLABORTED => {
+                println!("[{}] SASL error {:03}: {}", self.servers[id].name(), code, params.last().unwrap_or(&""));
+                self.sasl_done(id, code);
+            }
+            numeric::RPL_ENDOFMOTD | numeric::ERR_NOMOTD => {
+                // ISUPPORT has arrived by the end of the MOTD, so joins can be batched.
+                let delay = self.configs[id].nickserv.as_ref().is_some_and(|ns| ns.delay_join);
+                if delay && !self.identified[id] {
+                    let text = format!("Holding joins on {} until identified", self.servers[id].name());
+                    println!("{}", text);
+                    self.notify_clients(text.as_str());
+                    self.held_joins[id] = true;
+                    let eventtx = self.eventtx.clone();
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_secs(IDENTIFY_TIMEOUT));
+                        let _ = eventtx.send(Event::IdentifyTimeout(id));
+                    });
+                } else {
+                    self.autojoin(id);
+                }
+                self.watch_nick(id);
+            }
+            // The nick we wanted is free when MONOFFLINE lists it or ISON leaves it out.
+            numeric::RPL_MONOFFLINE | numeric::RPL_ISON if params.len() > 1 => {
+                let casemapping = self.servers[id].casemapping();
+                let primary = self.configs[id].nick.clone();
+                let listed = params[1].split([',', ' ']).any(|n| casemapping.eq(n, primary.as_str()));
+                if listed == (code == numeric::RPL_MONOFFLINE) && !self.servers[id].is_me(primary.as_str()) {
+                    self.send_srv(id, Message::nick(Hostmask::None, primary.as_str()).to_string());
+                }
+            }
+            _ => {
+                // Channel replies carry the channel at a fixed position; relay those to
+                // the clients in the namespaced channel, addressed to their own nick.
+                let chanpos = match code {
+                    numeric::RPL_TOPIC | numeric::RPL_NOTOPIC | numeric::RPL_TOPICWHOTIME |
+                    numeric::RPL_ENDOFNAMES | numeric::RPL_CHANNELMODEIS => Some(1),
+                    numeric::RPL_NAMREPLY => Some(2),
+                    _ => None
+                };
+                if code == numeric::RPL_NAMREPLY && params.len() > 3 {
+                    self.servers[id].add_names(params[2], params[3]);
+                }
+                match chanpos {
+                    Some(pos) if pos < params.len() => {
+                        let casemapping = self.servers[id].casemapping();
+                        let rawchan = self.rawchan(id, params[pos]);
+                        for client in &self.clients {
+                            if client.has_channel(rawchan.as_str(), casemapping) {
+                                let mut relayed = params.clone();
+                                let nick = client.nick();
+                                relayed[0] = nick.as_str();
+                                relayed[pos] = rawchan.as_str();
+                                self.send_clnt(client.id, Message::numeric(self.hostmask.clone(), code, &relayed).to_string());
+                            }
+                        }
+                    }
+                    _ => {
+                        if numeric::is_error(code) {
+                            println!("[{}] Error {:03}: {}", self.servers[id].name(), code, params.join(" "));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     fn handleclnt(&mut self, id: usize, msg: Message) {
         match msg.command {
             Command::USER(ref username, _, _, ref realname) => {
@@ -282,61 +842,56 @@ impl Bouncer {
             Command::PONG(ref param) => {
                 self.clients[id].register_pong();
             }
-            Command::JOIN(ref chans) => {
-                let chanlist = chans.split(',').map(|x| x.trim().split_at(1));
-                for (_, serverchan) in chanlist {
-                    let rawchan = "#".to_string()+serverchan;
-                    match serverchan.find('#') {
-                        Some(pos) => {
-                            let (server, chan) = serverchan.split_at(pos);
-                            match self.names.get(server) {
-                                Some(sid) => {
-                                    if !self.servers[*sid].has_channel(chan) {
-                                        self.send_srv(*sid, Message::join(Hostmask::None, chan).to_string());
-                                    }
-                                    self.clients[id].add_channel(rawchan.as_str());
+            Command::JOIN(ref chans, ref keys) => {
+                let mut keys = keys.split(',');
+                for rawchan in chans.split(',').map(|x| x.trim()) {
+                    let key = keys.next().unwrap_or("");
+                    match self.route(rawchan) {
+                        Some((sid, _)) if self.servers[sid].state().is_down() => {
+                            self.reject_down(id, sid, numeric::ERR_UNAVAILRESOURCE, rawchan);
+                        }
+                        Some((sid, ref chan)) if self.servers[sid].valid_channel(chan) => {
+                            if !self.servers[sid].has_channel(chan) {
+                                if !key.is_empty() {
+                                    let folded = self.servers[sid].casemapping().fold(chan);
+                                    self.pending_keys[sid].insert(folded, key.to_string());
                                 }
-                                None => println!("Server {} is unresolved", server)
+                                self.forward(sid, Message::join_keys(Hostmask::None, chan, key));
                             }
+                            self.clients[id].add_channel(rawchan, self.servers[sid].casemapping());
+                            self.send_clnt(id, Message::join(self.clients[id].hostmask(), rawchan).to_string());
                         }
-                        None => {
-                            println!("No channel #{} found", serverchan);
+                        _ => {
+                            println!("No channel {} found", rawchan);
+                            let nick = self.clients[id].nick();
+                            self.send_clnt(id, Message::numeric(self.hostmask.clone(), numeric::ERR_NOSUCHCHANNEL, &[nick.as_str(), rawchan, "No such channel"]).to_string());
                         }
                     }
-                    self.send_clnt(id, Message::join(self.clients[id].hostmask(), rawchan.as_str()).to_string());
                 }
             }
             Command::PRIVMSG(ref chan, ref message) => {
-                let (_, serverchan) = chan.split_at(1);
-                match serverchan.find('#') {
-                    Some(pos) => {
-                        let (server, target) = serverchan.split_at(pos);
-                        if self.clients[id].has_channel(chan.as_str()) {
-                            match self.names.get(server) {
-                                Some(sid) => self.send_srv(*sid, Message::privmsg(Hostmask::None, target, message).to_string()),
-                                None => println!("Server {} is unresolved", server)
-                            }
+                match self.route(chan) {
+                    Some((sid, target)) => {
+                        if self.clients[id].has_channel(chan.as_str(), self.servers[sid].casemapping()) &&
+                           !self.forward(sid, Message::privmsg(Hostmask::None, target.as_str(), message)) {
+                            self.reject_down(id, sid, numeric::ERR_CANNOTSENDTOCHAN, chan);
                         }
                     }
                     None => {
-                        println!("No channel #{} found", serverchan);
+                        println!("No channel {} found", chan);
                     }
                 }
             }
             Command::NOTICE(ref chan, ref message) => {
-                let (_, serverchan) = chan.split_at(1);
-                match serverchan.find('#') {
-                    Some(pos) => {
-                        let (server, target) = serverchan.split_at(pos);
-                        if self.clients[id].has_channel(chan.as_str()) {
-                            match self.names.get(server) {
-                                Some(sid) => self.send_srv(*sid, Message::notice(Hostmask::None, target, message).to_string()),
-                                None => println!("Server {} is unresolved", server)
-                            }
+                match self.route(chan) {
+                    Some((sid, target)) => {
+                        if self.clients[id].has_channel(chan.as_str(), self.servers[sid].casemapping()) &&
+                           !self.forward(sid, Message::notice(Hostmask::None, target.as_str(), message)) {
+                            self.reject_down(id, sid, numeric::ERR_CANNOTSENDTOCHAN, chan);
                         }
                     }
                     None => {
-                        println!("No channel #{} found", serverchan);
+                        println!("No channel {} found", chan);
                     }
                 }
             }
@@ -344,6 +899,219 @@ impl Bouncer {
         }
     }
 
+    /// The name clients know a server's channel by, e.g. `#freenode##doge`.
+    fn rawchan(&self, id: usize, chan: &str) -> String {
+        "#".to_string()+self.servers[id].name().as_str()+chan
+    }
+
+    /// Sends a message to every client in a namespaced channel, split to fit and
+    /// serialized once.
+    fn relay(&self, id: usize, rawchan: &str, msg: Message) {
+        let casemapping = self.servers[id].casemapping();
+        let lines : Vec<String> = msg.split(0).iter().map(|m| m.to_string()).collect();
+        for client in &self.clients {
+            if client.has_channel(rawchan, casemapping) {
+                for line in &lines {
+                    self.send_clnt(client.id, line.clone());
+                }
+            }
+        }
+    }
+
+    /// Sends a message about a user once to every client in any of the given
+    /// channels, which are the ones we share with that user.
+    fn relay_shared(&self, id: usize, chans: &[String], msg: Message) {
+        let casemapping = self.servers[id].casemapping();
+        let rawchans : Vec<String> = chans.iter().map(|chan| self.rawchan(id, chan)).collect();
+        let line = msg.to_string();
+        for client in &self.clients {
+            if rawchans.iter().any(|rawchan| client.has_channel(rawchan, casemapping)) {
+                self.send_clnt(client.id, line.clone());
+            }
+        }
+    }
+
+    /// Joins the configured channels, or after a reconnect the channels we were
+    /// in, with their keys.
+    fn autojoin(&mut self, id: usize) {
+        let rejoin = self.rejoins.remove(&id);
+        let mut chans = match rejoin {
+            Some(ref chans) => chans.clone(),
+            None => self.servers[id].config.chans.iter().map(|c| (c.clone(), None)).collect()
+        };
+        let limit = self.servers[id].with_isupport(|isupport| {
+            chans.retain(|(chan, _)| isupport.valid_channel(chan));
+            isupport.max_targets("JOIN").unwrap_or(chans.len()).max(1)
+        });
+        // Keyed channels go first so that keys line up with their channels.
+        chans.sort_by_key(|(_, key)| key.is_none());
+        let casemapping = self.servers[id].casemapping();
+        for (chan, key) in &chans {
+            if let Some(ref key) = *key {
+                self.pending_keys[id].insert(casemapping.fold(chan), key.clone());
+            }
+        }
+        for group in chans.chunks(limit) {
+            let names : Vec<&str> = group.iter().map(|(chan, _)| chan.as_str()).collect();
+            let keys : Vec<&str> = group.iter().filter_map(|(_, key)| key.as_ref().map(|k| k.as_str())).collect();
+            self.send_srv(id, Message::join_keys(Hostmask::None, names.join(",").as_str(), keys.join(",").as_str()).to_string());
+        }
+        if rejoin.is_some() {
+            let text = format!("Reconnected to {}", self.servers[id].name());
+            for (chan, _) in &chans {
+                let rawchan = self.rawchan(id, chan);
+                self.relay(id, rawchan.as_str(), Message::notice(self.hostmask.clone(), rawchan.as_str(), text.as_str()));
+            }
+        }
+    }
+
+    /// Logs in with services once registered, unless SASL already did, and
+    /// ghosts whoever holds our nick so that watching for it picks it up.
+    fn identify(&mut self, id: usize) {
+        let ns = match self.configs[id].nickserv {
+            Some(ref ns) => ns.clone(),
+            None => return
+        };
+        let primary = self.configs[id].nick.clone();
+        if !self.identified[id] && !ns.identify.is_empty() {
+            let command = ns.command(ns.identify.as_str(), primary.as_str());
+            self.send_srv(id, Message::privmsg(Hostmask::None, ns.service.as_str(), command.as_str()).to_string());
+        }
+        if !ns.ghost.is_empty() && !self.servers[id].is_me(primary.as_str()) {
+            println!("[{}] Asking {} to free {}", self.servers[id].name(), ns.service, primary);
+            let command = ns.command(ns.ghost.as_str(), primary.as_str());
+            self.send_srv(id, Message::privmsg(Hostmask::None, ns.service.as_str(), command.as_str()).to_string());
+        }
+    }
+
+    /// Services confirmed our login; joins held back for it can go ahead.
+    fn mark_identified(&mut self, id: usize) {
+        self.identified[id] = true;
+        if self.held_joins[id] {
+            self.held_joins[id] = false;
+            self.autojoin(id);
+        }
+    }
+
+    /// Joins are still held well after we asked to identify, so the clients
+    /// should know their channels won't be coming.
+    fn identify_timeout(&mut self, id: usize) {
+        if self.held_joins[id] {
+            let text = format!("Still not identified on {} after {}s, joins stay held", self.servers[id].name(), IDENTIFY_TIMEOUT);
+            println!("{}", text);
+            self.notify_clients(text.as_str());
+        }
+    }
+
+    /// Not every services package sends RPL_LOGGEDIN, so look for the usual
+    /// confirmations in notices from the service too. Failures are passed on
+    /// to the clients, and once our ghost is gone we take the nick back.
+    fn nickserv_notice(&mut self, id: usize, msg: &MessageRef) {
+        let casemapping = self.servers[id].casemapping();
+        let service = match self.configs[id].nickserv {
+            Some(ref ns) if msg.nick().is_some_and(|n| casemapping.eq(n, ns.service.as_str())) => ns.service.clone(),
+            _ => return
+        };
+        let text = msg.param(1).to_lowercase();
+        if text.contains("you are now identified") || text.contains("password accepted") {
+            if !self.identified[id] {
+                self.mark_identified(id);
+            }
+        } else if text.contains("invalid password") || text.contains("incorrect password") || text.contains("password incorrect") ||
+            text.contains("isn't registered") || text.contains("is not registered") {
+            let text = format!("{} on {}: {}", service, self.servers[id].name(), msg.param(1));
+            println!("{}", text);
+            self.notify_clients(text.as_str());
+        } else if text.contains("has been ghosted") || text.contains("has been killed") {
+            let primary = self.configs[id].nick.clone();
+            if !self.servers[id].is_me(primary.as_str()) {
+                self.send_srv(id, Message::nick(Hostmask::None, primary.as_str()).to_string());
+            }
+        }
+    }
+
+    /// Starts watching for the configured nick to come free if we had to settle
+    /// for another one, with MONITOR where the server has it and ISON otherwise.
+    fn watch_nick(&mut self, id: usize) {
+        let primary = self.configs[id].nick.clone();
+        if self.servers[id].is_me(primary.as_str()) {
+            return;
+        }
+        if self.servers[id].has_isupport("MONITOR") {
+            self.send_srv(id, Message::other(Hostmask::None, "MONITOR", &["+", primary.as_str()]).to_string());
+        } else {
+            self.check_nick(id);
+        }
+    }
+
+    fn check_nick(&mut self, id: usize) {
+        let primary = self.configs[id].nick.clone();
+        if self.servers[id].state() != ServerState::Registered || self.servers[id].is_me(primary.as_str()) {
+            return;
+        }
+        self.send_srv(id, Message::ison(Hostmask::None, &[primary.as_str()]).to_string());
+        // One timer per network at a time, however often we get here.
+        if !self.ison_timers[id] {
+            self.ison_timers[id] = true;
+            let eventtx = self.eventtx.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(ISON_INTERVAL));
+                let _ = eventtx.send(Event::CheckNick(id));
+            });
+        }
+    }
+
+    /// Our nick on a network changed; stop watching if it is the one we wanted
+    /// and tell the clients in each of its channels.
+    fn nick_changed(&mut self, id: usize) {
+        let nick = self.servers[id].get_nick();
+        if self.servers[id].is_me(self.configs[id].nick.as_str()) && self.servers[id].has_isupport("MONITOR") {
+            self.send_srv(id, Message::other(Hostmask::None, "MONITOR", &["-", nick.as_str()]).to_string());
+        }
+        let text = format!("You are now known as {} on {}", nick, self.servers[id].name());
+        for chan in self.servers[id].channels() {
+            let rawchan = self.rawchan(id, chan.name.as_str());
+            self.relay(id, rawchan.as_str(), Message::notice(self.hostmask.clone(), rawchan.as_str(), text.as_str()));
+        }
+    }
+
+    /// Resolves a namespaced channel such as `#freenode##doge` to the server it
+    /// belongs to and the channel name on that server, using the server's CHANTYPES.
+    fn route(&self, rawchan: &str) -> Option<(usize, String)> {
+        let serverchan = rawchan.strip_prefix('#')?;
+        self.names.iter()
+            .filter(|&(name, _)| serverchan.starts_with(name.as_str()))
+            .map(|(name, &sid)| (sid, &serverchan[name.len()..]))
+            .filter(|&(sid, chan)| self.servers[sid].is_channel(chan))
+            .min_by_key(|&(_, chan)| chan.len())
+            .map(|(sid, chan)| (sid, chan.to_string()))
+    }
+
+    /// Answers a CTCP query on behalf of the user while no client is attached,
+    /// within the limits of `CtcpLimit`.
+    fn answer_ctcp(&mut self, id: usize, hostmask: &Hostmask, ctcp: &Ctcp) {
+        if !self.servers[id].config.ctcp_replies {
+            return;
+        }
+        let nick = match hostmask.nick() {
+            Some(nick) => nick,
+            None => return
+        };
+        let reply = match ctcp.command.as_str() {
+            "VERSION" => format!("carbon {}", env!("CARGO_PKG_VERSION")),
+            "PING" => ctcp.params.clone(),
+            "TIME" => Local::now().to_rfc2822(),
+            "CLIENTINFO" => "ACTION CLIENTINFO PING TIME VERSION".to_string(),
+            _ => return
+        };
+        let sender = self.servers[id].casemapping().fold(nick);
+        if !self.ctcp_limits[id].allow(sender) {
+            println!("[{}] Not answering CTCP {} from {}, too many queries", self.servers[id].name(), ctcp.command, nick);
+            return;
+        }
+        self.send_srv_msg(id, Message::ctcp_reply(Hostmask::None, nick, ctcp.command.as_str(), reply.as_str()));
+    }
+
     fn send_srv(&self, id: usize, line: String) {
         self.srvsendtxs[id].send(line);
     }
@@ -351,4 +1119,64 @@ impl Bouncer {
     fn send_clnt(&self, id: usize, line: String) {
         self.clntsendtxs[id].send(line);
     }
+
+    /// Sends a client's message upstream, holding it back while the network is
+    /// still registering. Returns false if the network is down.
+    fn forward(&mut self, id: usize, msg: Message) -> bool {
+        let state = self.servers[id].state();
+        if state.is_down() {
+            return false;
+        }
+        for part in msg.split(self.servers[id].prefix_len()) {
+            match state {
+                ServerState::Registered => self.send_srv(id, part.to_string()),
+                _ => self.pending[id].push(part.to_string())
+            }
+        }
+        true
+    }
+
+    fn reject_down(&self, id: usize, sid: usize, code: u16, target: &str) {
+        let nick = self.clients[id].nick();
+        let text = format!("Network {} is down, try again later", self.servers[sid].name());
+        self.send_clnt(id, Message::numeric(self.hostmask.clone(), code, &[nick.as_str(), target, text.as_str()]).to_string());
+    }
+
+    /// Tells every attached client about something the bouncer itself ran into.
+    fn notify_clients(&self, text: &str) {
+        for client in self.clients.iter().filter(|c| c.is_connected()) {
+            self.send_clnt(client.id, Message::notice(self.hostmask.clone(), client.nick().as_str(), text).to_string());
+        }
+    }
+
+    /// Sends a message upstream, split to fit once the server adds our prefix.
+    fn send_srv_msg(&self, id: usize, msg: Message) {
+        for part in msg.split(self.servers[id].prefix_len()) {
+            self.send_srv(id, part.to_string());
+        }
+    }
+}
+
+/// Reads pins saved by `save_pin`, one `network fingerprint` pair per line.
+fn load_pins(path: &str) -> Vec<(String, String)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(err) => {
+            println!("Could not read pinned certificates from {}: {}", path, err);
+            return Vec::new();
+        }
+    };
+    contents.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(name), Some(fingerprint)) => Some((name.to_string(), fingerprint.to_string())),
+            _ => None
+        }
+    }).collect()
+}
+
+fn save_pin(path: &str, name: &str, fingerprint: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).mode(0o600).open(path)?;
+    writeln!(file, "{} {}", name, fingerprint)
 }
@@ -2,23 +2,25 @@
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::borrow::Borrow;
-use std::net::{TcpListener, TcpStream};
 use std::ops::{Deref, DerefMut};
 use std::thread;
 use std::time::Duration;
 
+use openssl::ssl::SslAcceptor;
+
 use bouncer::Event;
 use irc::server::Server;
 use irc::client::Client;
 use irc::message::Message;
+use irc::stream::{ListenSocket, Stream};
 use irc::Connection;
 
 pub struct PingWorker<T: Connection> {
     conn : T,
 }
 
-impl<T: Connection> PingWorker<T> {
-    pub fn new(conn: T) -> PingWorker<T> {
+impl PingWorker<Client> {
+    pub fn new(conn: Client) -> PingWorker<Client> {
         PingWorker { conn: conn }
     }
 
@@ -34,6 +36,30 @@ impl<T: Connection> PingWorker<T> {
     }
 }
 
+/// Asks the bouncer to ping a network every so often, for as long as the
+/// connection it was started on is up. Whether the pong came back is the
+/// bouncer's to judge.
+pub struct PingTimer {
+    conn : Server,
+    tx : Sender<Event>,
+}
+
+impl PingTimer {
+    pub fn new(conn: Server, tx: Sender<Event>) -> PingTimer {
+        PingTimer { conn: conn, tx: tx }
+    }
+
+    pub fn work(&mut self) {
+        loop {
+            thread::sleep(Duration::from_secs(255));
+            if !self.conn.is_connected() || self.tx.send(Event::PingServer(self.conn.id())).is_err() {
+                break;
+            }
+        }
+        println!("Dropping ping thread {:?}", thread::current().name());
+    }
+}
+
 pub struct SendWorker<T: Connection> {
     conn : T,
     rx : Receiver<String>
@@ -48,7 +74,7 @@ impl<T: Connection> SendWorker<T> {
         while self.conn.is_connected() {
             match self.rx.recv() {
                 Ok(string) => {
-                    println!("[{}] <= {}", self.conn.name(), string);
+                    println!("[{}] <= {}", self.conn.name(), self.conn.loggable(string.as_str()));
                     self.conn.send(string);
                 }
                 Err(err) => println!("Error: {}", err)
@@ -72,12 +98,12 @@ impl ReadWorker<Server> {
         if let Ok(mut iter) = self.conn.read() {
             for line in iter {
                 println!("[{}] => {}", self.conn.name(), line);
-                self.tx.send(Event::ServerRead(self.conn.id(), Message::from_string(line.as_str())));
+                self.tx.send(Event::ServerRead(self.conn.id(), line));
             }
         } else {
             println!("Error reading from IRC");
         }
-        self.conn.set_connected(false);
+        let _ = self.tx.send(Event::ServerDisconnect(self.conn.id()));
         println!("Dropping read thread");
     }
 }
@@ -102,22 +128,35 @@ impl ReadWorker<Client> {
 }
 
 pub struct Listener {
-    listener : TcpListener,
+    listener : ListenSocket,
+    acceptor : Option<SslAcceptor>,
     eventtx : Sender<Event>,
 }
 
 impl Listener {
-    pub fn new(listener: TcpListener, eventtx: Sender<Event>) -> Listener {
-        Listener { listener: listener, eventtx: eventtx }
+    pub fn new(listener: ListenSocket, acceptor: Option<SslAcceptor>, eventtx: Sender<Event>) -> Listener {
+        Listener { listener: listener, acceptor: acceptor, eventtx: eventtx }
     }
 
     pub fn work(&mut self) {
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.eventtx.send(Event::AcceptConn(Arc::new(Mutex::new(stream))));
+        loop {
+            match (self.listener.accept(), self.acceptor.clone()) {
+                (Ok(stream), None) => {
+                    self.eventtx.send(Event::AcceptConn(Arc::new(Mutex::new(Stream::Plain(stream)))));
+                }
+                (Ok(stream), Some(acceptor)) => {
+                    // Handshake off the accept loop so a stalled client can't hold up others.
+                    let eventtx = self.eventtx.clone();
+                    thread::spawn(move || {
+                        match Stream::accept(&acceptor, stream) {
+                            Ok(stream) => {
+                                let _ = eventtx.send(Event::AcceptConn(Arc::new(Mutex::new(stream))));
+                            }
+                            Err(err) => println!("TLS handshake with client failed: {}", err)
+                        }
+                    });
                 }
-                Err(e) => {
+                (Err(e), _) => {
                     println!("Failed to accept connection");
                 }
             }
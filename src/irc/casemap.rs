@@ -0,0 +1,60 @@
+// RFC 1459 rules apply until the server says otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Casemapping {
+    Ascii,
+    #[default]
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl Casemapping {
+    /// Resolves a CASEMAPPING token; unknown mappings yield None.
+    pub fn from_name(name: &str) -> Option<Casemapping> {
+        match name.to_lowercase().as_str() {
+            "ascii" => Some(Casemapping::Ascii),
+            "rfc1459" => Some(Casemapping::Rfc1459),
+            "strict-rfc1459" => Some(Casemapping::StrictRfc1459),
+            _ => None
+        }
+    }
+
+    pub fn fold_char(self, c: char) -> char {
+        match (self, c) {
+            (Casemapping::Rfc1459, '^') => '~',
+            (Casemapping::Rfc1459, '[') | (Casemapping::StrictRfc1459, '[') => '{',
+            (Casemapping::Rfc1459, ']') | (Casemapping::StrictRfc1459, ']') => '}',
+            (Casemapping::Rfc1459, '\\') | (Casemapping::StrictRfc1459, '\\') => '|',
+            (_, c) => c.to_ascii_lowercase()
+        }
+    }
+
+    pub fn fold(self, string: &str) -> String {
+        string.chars().map(|c| self.fold_char(c)).collect()
+    }
+
+    pub fn eq(self, a: &str, b: &str) -> bool {
+        a.len() == b.len() && a.chars().zip(b.chars()).all(|(x, y)| self.fold_char(x) == self.fold_char(y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_by_mapping() {
+        assert_eq!(Casemapping::Rfc1459.fold("Nick[]\\^"), "nick{}|~");
+        assert_eq!(Casemapping::StrictRfc1459.fold("Nick[]\\^"), "nick{}|^");
+        assert_eq!(Casemapping::Ascii.fold("Nick[]\\^"), "nick[]\\^");
+        assert_eq!(Casemapping::Ascii.fold("\u{c9}"), "\u{c9}");
+    }
+
+    #[test]
+    fn compares_names() {
+        assert!(Casemapping::Rfc1459.eq("#Foo[1]", "#fOO{1}"));
+        assert!(!Casemapping::Ascii.eq("#Foo[1]", "#fOO{1}"));
+        assert!(!Casemapping::Rfc1459.eq("nick", "nick_"));
+        assert_eq!(Casemapping::from_name("STRICT-RFC1459"), Some(Casemapping::StrictRfc1459));
+        assert_eq!(Casemapping::from_name("rfc7613"), None);
+    }
+}
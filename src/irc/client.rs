@@ -1,11 +1,12 @@
 
-use std::io::{self, BufReader, BufRead, Lines, Write};
-use std::iter::FilterMap;
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, Write};
 use std::sync::{Arc, Mutex};
 use std::ops::{Deref, DerefMut};
 
 use irc::Connection;
+use irc::codec::{Codec, LineReader};
+use irc::stream::Stream;
+use irc::casemap::Casemapping;
 use irc::message::{Message, Hostmask};
 
 struct Userdata {
@@ -21,19 +22,20 @@ pub struct Client {
     nick : Arc<Mutex<String>>,
     pub id : usize,
     name : String,
-    stream : TcpStream,
+    stream : Stream,
+    codec : Codec,
     channels : Arc<Mutex<Vec<String>>>,
 }
 
 impl Client {
-    pub fn from_stream(id: usize, stream: TcpStream) -> Client {
-        let userdata = Userdata { username: "".to_string(), hostname: format!("{}", stream.peer_addr().unwrap().ip()), realname: "".to_string() };
-        Client { id: id, stream: stream, connected: Arc::new(Mutex::new(true)), userdata: Arc::new(Mutex::new(userdata)), nick: Arc::new(Mutex::new("".to_string())), ping_active: Arc::new(Mutex::new(false)), name: "Client".to_string(), channels: Arc::new(Mutex::new(Vec::new())) }
+    pub fn from_stream(id: usize, stream: Stream) -> Client {
+        let userdata = Userdata { username: "".to_string(), hostname: stream.peer_host(), realname: "".to_string() };
+        Client { id: id, stream: stream, codec: Codec::default(), connected: Arc::new(Mutex::new(true)), userdata: Arc::new(Mutex::new(userdata)), nick: Arc::new(Mutex::new("".to_string())), ping_active: Arc::new(Mutex::new(false)), name: "Client".to_string(), channels: Arc::new(Mutex::new(Vec::new())) }
     }
 
     pub fn try_clone(&self) -> io::Result<Client> {
         match self.stream.try_clone() {
-            Ok(stream) => Ok(Client { id: self.id, stream: stream, connected: self.connected.clone(), userdata: self.userdata.clone(), nick: self.nick.clone(), ping_active: self.ping_active.clone(), name: self.name.clone(), channels: self.channels.clone() }),
+            Ok(stream) => Ok(Client { id: self.id, stream: stream, codec: self.codec, connected: self.connected.clone(), userdata: self.userdata.clone(), nick: self.nick.clone(), ping_active: self.ping_active.clone(), name: self.name.clone(), channels: self.channels.clone() }),
             Err(err) => Err(err)
         }
     }
@@ -70,18 +72,40 @@ impl Client {
     }
 
     pub fn welcome_msg(&self, hostmask: Hostmask) -> Message {
-        let param = format!("{} :Welcome to the Internet Relay Network {}", self.nick(), self.hostmask());
-        Message::rpl_welcome(hostmask, param.as_str())
+        let text = format!("Welcome to the Internet Relay Network {}", self.hostmask());
+        Message::rpl_welcome(hostmask, self.nick().as_str(), text.as_str())
     }
 
-    pub fn has_channel(&self, channel: &str) -> bool {
-        let mut channels = self.channels.lock().unwrap();
-        channels.deref().contains(&channel.to_string())
+    /// Namespaced channels compare under the casemapping of the network they belong to.
+    pub fn has_channel(&self, channel: &str, casemapping: Casemapping) -> bool {
+        let channels = self.channels.lock().unwrap();
+        channels.deref().iter().any(|c| casemapping.eq(c, channel))
     }
 
-    pub fn add_channel(&mut self, channel: &str) {
-        let mut channels = self.channels.lock().unwrap();
-        channels.deref_mut().push(channel.to_string());
+    pub fn add_channel(&mut self, channel: &str, casemapping: Casemapping) {
+        if !self.has_channel(channel, casemapping) {
+            let mut channels = self.channels.lock().unwrap();
+            channels.deref_mut().push(channel.to_string());
+        }
+    }
+
+    pub fn try_ping(&mut self) -> bool {
+        let ping_active = self.ping_active.clone();
+        if *ping_active.lock().unwrap().deref() {
+            self.set_connected(false);
+            false
+        } else {
+            let string = Message::ping(Hostmask::None, "carbon").to_string();
+            println!("[{}] <= {}", self.name(), string);
+            *ping_active.lock().unwrap().deref_mut() = true;
+            self.send(string);
+            true
+        }
+    }
+
+    pub fn register_pong(&mut self) {
+        let mut ping_active = self.ping_active.lock().unwrap();
+        *ping_active.deref_mut() = false;
     }
 }
 
@@ -103,34 +127,15 @@ impl Connection for Client {
         *connected.deref_mut() = value;
     }
 
-    fn try_ping(&mut self) -> bool {
-        let mut ping_active = self.ping_active.clone();
-        if *ping_active.lock().unwrap().deref() {
-            self.set_connected(false);
-            false
-        } else {
-            let string = Message::ping(Hostmask::None, ":carbon").to_string();
-            println!("[{}] <= {}", self.name(), string);
-            *ping_active.lock().unwrap().deref_mut() = true;
-            self.send(string);
-            true
-        }
-    }
-
-    fn register_pong(&mut self) {
-        let mut ping_active = self.ping_active.lock().unwrap();
-        *ping_active.deref_mut() = false;
-    }
-
     fn send(&mut self, string: String) -> io::Result<()> {
         let clone = self.try_clone();
         let newline = |mut this: Client| this.stream.write_all(b"\r\n");
-        self.stream.write_all(string.as_bytes()).and(clone).and_then(newline)
+        self.stream.write_all(&self.codec.encode(string.as_str())).and(clone).and_then(newline)
     }
 
-    fn read(&mut self) -> io::Result<FilterMap<Lines<BufReader<TcpStream>>, fn(io::Result<String>) -> Option<String>>> {
+    fn read(&mut self) -> io::Result<LineReader<Stream>> {
         match self.stream.try_clone() {
-            Ok(stream) => Ok(BufReader::new(stream).lines().filter_map(Result::ok)),
+            Ok(stream) => Ok(LineReader::new(stream, self.codec)),
             Err(err) => Err(err)
         }
     }
@@ -0,0 +1,108 @@
+use std::io::{BufRead, BufReader, Read};
+
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+
+/// Converts between a network's character encoding and the UTF-8 used internally.
+#[derive(Clone, Copy, Debug)]
+pub struct Codec {
+    encoding : &'static Encoding,
+    fallback : &'static Encoding,
+}
+
+impl Default for Codec {
+    // Old networks are mostly latin-1, which windows-1252 decodes losslessly.
+    fn default() -> Codec {
+        Codec { encoding: UTF_8, fallback: WINDOWS_1252 }
+    }
+}
+
+impl Codec {
+    /// Looks up both encodings by their WHATWG labels, e.g. `utf-8` or `latin1`.
+    pub fn new(encoding: &str, fallback: &str) -> Option<Codec> {
+        let encoding = Encoding::for_label(encoding.as_bytes())?;
+        let fallback = Encoding::for_label(fallback.as_bytes())?;
+        Some(Codec { encoding, fallback })
+    }
+
+    pub fn is_known(label: &str) -> bool {
+        Encoding::for_label(label.as_bytes()).is_some()
+    }
+
+    /// Decodes a line. Lines that are not valid in the network's encoding are
+    /// decoded with the fallback instead of being dropped.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        match self.encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+            Some(string) => string.into_owned(),
+            None => self.fallback.decode_without_bom_handling(bytes).0.into_owned()
+        }
+    }
+
+    /// Encodes a line, substituting '?' for characters the encoding cannot represent.
+    pub fn encode(&self, string: &str) -> Vec<u8> {
+        if self.encoding == UTF_8 {
+            return string.as_bytes().to_vec();
+        }
+        let mut bytes = Vec::with_capacity(string.len());
+        let mut buf = [0; 4];
+        for c in string.chars() {
+            let (encoded, _, unmappable) = self.encoding.encode(c.encode_utf8(&mut buf));
+            if unmappable {
+                bytes.push(b'?');
+            } else {
+                bytes.extend_from_slice(&encoded);
+            }
+        }
+        bytes
+    }
+}
+
+/// Reads CRLF-terminated lines as raw bytes and decodes them with a `Codec`.
+pub struct LineReader<R: Read> {
+    reader : BufReader<R>,
+    codec : Codec,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(stream: R, codec: Codec) -> LineReader<R> {
+        LineReader { reader: BufReader::new(stream), codec }
+    }
+}
+
+impl<R: Read> Iterator for LineReader<R> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut buf = Vec::new();
+        match self.reader.read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                while buf.last() == Some(&b'\n') || buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+                Some(self.codec.decode(&buf))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_with_fallback() {
+        let codec = Codec::default();
+        assert_eq!(codec.decode("caf\u{e9}".as_bytes()), "caf\u{e9}");
+        assert_eq!(codec.decode(b"caf\xe9"), "caf\u{e9}");
+        assert_eq!(codec.decode(b"\x93quoted\x94"), "\u{201c}quoted\u{201d}");
+    }
+
+    #[test]
+    fn encodes_unmappable_as_question_marks() {
+        let latin1 = Codec::new("latin1", "utf-8").unwrap();
+        assert_eq!(latin1.encode("caf\u{e9}"), b"caf\xe9");
+        assert_eq!(latin1.encode("\u{263a} ok \u{1f600}"), b"? ok ?");
+        assert_eq!(Codec::default().encode("\u{263a}"), "\u{263a}".as_bytes());
+        assert!(Codec::new("no-such-encoding", "utf-8").is_none());
+    }
+}
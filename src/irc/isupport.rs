@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use irc::casemap::Casemapping;
+use irc::message::ModeKind;
+
+/// The four CHANMODES classes: list modes, modes that always take a parameter,
+/// modes that take one only when set, and plain flags.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChanModes {
+    pub list : String,
+    pub always : String,
+    pub set : String,
+    pub flag : String,
+}
+
+/// Features a network advertises through RPL_ISUPPORT, with RFC defaults for
+/// anything it leaves out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ISupport {
+    pub chantypes : String,
+    pub prefix : Vec<(char, char)>,
+    pub chanmodes : ChanModes,
+    pub casemapping : Casemapping,
+    pub network : Option<String>,
+    pub nicklen : Option<usize>,
+    pub channellen : Option<usize>,
+    pub topiclen : Option<usize>,
+    pub modes : Option<usize>,
+    pub targmax : HashMap<String, Option<usize>>,
+    pub tokens : HashMap<String, String>,
+}
+
+impl Default for ISupport {
+    fn default() -> ISupport {
+        ISupport {
+            chantypes: "#&".to_string(),
+            prefix: vec![('o', '@'), ('v', '+')],
+            chanmodes: ChanModes { list: "b".to_string(), always: "k".to_string(), set: "l".to_string(), flag: "imnpst".to_string() },
+            casemapping: Casemapping::default(),
+            network: None,
+            nicklen: None,
+            channellen: None,
+            topiclen: None,
+            modes: Some(3),
+            targmax: HashMap::new(),
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+impl ISupport {
+    pub fn new() -> ISupport {
+        ISupport::default()
+    }
+
+    /// Applies the tokens of one RPL_ISUPPORT line, without the leading nick
+    /// and the trailing "are supported by this server" text.
+    pub fn update(&mut self, tokens: &[String]) {
+        for token in tokens {
+            if let Some(name) = token.strip_prefix('-') {
+                self.reset(name);
+                continue;
+            }
+            let mut pair = token.splitn(2, '=');
+            let name = pair.next().unwrap_or("").to_uppercase();
+            let value = pair.next().unwrap_or("");
+            self.set(name.as_str(), value);
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) {
+        let number = value.parse().ok();
+        match name {
+            "CHANTYPES" => self.chantypes = value.to_string(),
+            "PREFIX" => {
+                // (modes)symbols, e.g. (ov)@+
+                let mut halves = value.trim_start_matches('(').splitn(2, ')');
+                let modes = halves.next().unwrap_or("");
+                let symbols = halves.next().unwrap_or("");
+                self.prefix = modes.chars().zip(symbols.chars()).collect();
+            }
+            "CHANMODES" => {
+                let mut classes = value.split(',').map(|c| c.to_string());
+                self.chanmodes = ChanModes {
+                    list: classes.next().unwrap_or_default(),
+                    always: classes.next().unwrap_or_default(),
+                    set: classes.next().unwrap_or_default(),
+                    flag: classes.next().unwrap_or_default(),
+                };
+            }
+            "CASEMAPPING" => {
+                if let Some(casemapping) = Casemapping::from_name(value) {
+                    self.casemapping = casemapping;
+                }
+            }
+            "NETWORK" => self.network = Some(value.to_string()),
+            "NICKLEN" => self.nicklen = number,
+            "CHANNELLEN" => self.channellen = number,
+            "TOPICLEN" => self.topiclen = number,
+            "MODES" => self.modes = number,
+            "TARGMAX" => {
+                self.targmax.clear();
+                for limit in value.split(',').filter(|l| !l.is_empty()) {
+                    let mut pair = limit.splitn(2, ':');
+                    let command = pair.next().unwrap_or("").to_uppercase();
+                    self.targmax.insert(command, pair.next().and_then(|n| n.parse().ok()));
+                }
+            }
+            _ => ()
+        }
+        self.tokens.insert(name.to_string(), value.to_string());
+    }
+
+    fn reset(&mut self, name: &str) {
+        let name = name.to_uppercase();
+        let defaults = ISupport::default();
+        match name.as_str() {
+            "CHANTYPES" => self.chantypes = defaults.chantypes,
+            "PREFIX" => self.prefix = defaults.prefix,
+            "CHANMODES" => self.chanmodes = defaults.chanmodes,
+            "CASEMAPPING" => self.casemapping = defaults.casemapping,
+            "NETWORK" => self.network = defaults.network,
+            "NICKLEN" => self.nicklen = defaults.nicklen,
+            "CHANNELLEN" => self.channellen = defaults.channellen,
+            "TOPICLEN" => self.topiclen = defaults.topiclen,
+            "MODES" => self.modes = defaults.modes,
+            "TARGMAX" => self.targmax.clear(),
+            _ => ()
+        }
+        self.tokens.remove(&name);
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        match name.chars().next() {
+            Some(c) => self.chantypes.contains(c),
+            None => false
+        }
+    }
+
+    /// The most targets a command accepts at once, or None when unlimited.
+    /// Without TARGMAX every command is assumed to take a single target.
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        if !self.tokens.contains_key("TARGMAX") {
+            return Some(1);
+        }
+        match self.targmax.get(&command.to_uppercase()) {
+            Some(limit) => *limit,
+            None => Some(1)
+        }
+    }
+
+    /// Classifies a channel mode letter. Letters the server did not announce are
+    /// treated as flags, so they never swallow a parameter.
+    pub fn mode_kind(&self, mode: char) -> ModeKind {
+        if self.prefix.iter().any(|&(m, _)| m == mode) {
+            ModeKind::Prefix
+        } else if self.chanmodes.list.contains(mode) {
+            ModeKind::List
+        } else if self.chanmodes.always.contains(mode) {
+            ModeKind::Always
+        } else if self.chanmodes.set.contains(mode) {
+            ModeKind::Set
+        } else {
+            ModeKind::Flag
+        }
+    }
+
+    /// The prefix mode letter for a NAMES/WHO status symbol such as '@'.
+    pub fn prefix_mode(&self, symbol: char) -> Option<char> {
+        self.prefix.iter().find(|&&(_, s)| s == symbol).map(|&(m, _)| m)
+    }
+
+    /// Whether a channel name fits within CHANNELLEN.
+    pub fn valid_channel(&self, name: &str) -> bool {
+        self.is_channel(name) && self.channellen.is_none_or(|len| name.len() <= len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::message::ModeChange;
+
+    fn tokens(line: &str) -> Vec<String> {
+        line.split(' ').map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn applies_tokens() {
+        let mut isupport = ISupport::new();
+        isupport.update(&tokens("PREFIX=(qaohv)~&@%+ CHANTYPES=#! CHANMODES=beI,k,l,imnst TARGMAX=JOIN:4,PRIVMSG: CASEMAPPING=ascii CHANNELLEN=10"));
+        assert!(isupport.is_channel("!chan"));
+        assert!(!isupport.is_channel("&chan"));
+        assert_eq!(isupport.prefix_mode('%'), Some('h'));
+        assert_eq!(isupport.max_targets("join"), Some(4));
+        assert_eq!(isupport.max_targets("PRIVMSG"), None);
+        assert_eq!(isupport.max_targets("KICK"), Some(1));
+        assert_eq!(isupport.casemapping, Casemapping::Ascii);
+        assert!(isupport.valid_channel("#ten-chars"));
+        assert!(!isupport.valid_channel("#eleven-chr"));
+        assert_eq!(isupport.mode_kind('h'), ModeKind::Prefix);
+        assert_eq!(isupport.mode_kind('I'), ModeKind::List);
+        assert_eq!(isupport.mode_kind('z'), ModeKind::Flag);
+
+        isupport.update(&tokens("-CHANTYPES -TARGMAX"));
+        assert_eq!(isupport.chantypes, "#&");
+        assert_eq!(isupport.max_targets("JOIN"), Some(1));
+        assert!(!isupport.tokens.contains_key("CHANTYPES"));
+    }
+
+    #[test]
+    fn parses_modes() {
+        let mut isupport = ISupport::new();
+        isupport.update(&tokens("CHANMODES=b,k,l,imnst"));
+        let changes = ModeChange::parse(&["+ov-k+bl-l", "a", "b", "key", "*!*@*", "10"], &isupport);
+        let summary : Vec<(bool, char, Option<&str>)> = changes.iter().map(|c| (c.adding, c.mode, c.param.as_deref())).collect();
+        assert_eq!(summary, vec![
+            (true, 'o', Some("a")),
+            (true, 'v', Some("b")),
+            (false, 'k', Some("key")),
+            (true, 'b', Some("*!*@*")),
+            (true, 'l', Some("10")),
+            (false, 'l', None),
+        ]);
+    }
+}
@@ -1,8 +1,14 @@
+use std::borrow::Cow;
 use std::fmt;
 
+use irc::casemap::Casemapping;
+use irc::isupport::ISupport;
+use irc::numeric;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Hostmask {
     User(String, String, String),
+    Nick(String),
     Server(String),
     None,
 }
@@ -10,211 +16,1097 @@ pub enum Hostmask {
 impl fmt::Display for Hostmask {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Hostmask::User(ref nick, ref user, ref host) => write!(f, "{}!{}@{}", nick, user, host),
+            Hostmask::User(ref nick, ref user, ref host) => {
+                write!(f, "{}", nick)?;
+                if !user.is_empty() {
+                    write!(f, "!{}", user)?;
+                }
+                if !host.is_empty() {
+                    write!(f, "@{}", host)?;
+                }
+                Ok(())
+            }
+            Hostmask::Nick(ref nick) => write!(f, "{}", nick),
             Hostmask::Server(ref server) => write!(f, "{}", server),
             Hostmask::None => write!(f, "")
         }
     }
 }
 
-#[derive(Clone, Debug)]
+impl Hostmask {
+    /// Parses a message prefix. `nick!user@host` and its partial forms are users,
+    /// a bare name is a server if it contains a dot and a nick otherwise.
+    pub fn from_string(prefix: &str) -> Hostmask {
+        if prefix.is_empty() {
+            return Hostmask::None;
+        }
+        match prefix.find(['!', '@']) {
+            Some(pos) => {
+                let nick = &prefix[..pos];
+                let rest = &prefix[pos..];
+                let (user, host) = match rest.find('@') {
+                    Some(at) => (&rest[..at], &rest[at + 1..]),
+                    None => (rest, "")
+                };
+                Hostmask::User(nick.to_string(), user.trim_start_matches('!').to_string(), host.to_string())
+            }
+            None => {
+                if prefix.contains('.') {
+                    Hostmask::Server(prefix.to_string())
+                } else {
+                    Hostmask::Nick(prefix.to_string())
+                }
+            }
+        }
+    }
+
+    /// The nick of a user prefix, if this is one.
+    pub fn nick(&self) -> Option<&str> {
+        match *self {
+            Hostmask::User(ref nick, _, _) | Hostmask::Nick(ref nick) => Some(nick.as_str()),
+            _ => None
+        }
+    }
+
+    /// Checks this prefix against a `*`/`?` mask such as a ban or ignore entry.
+    /// Partial masks are completed first, so `nick` means `nick!*@*`.
+    #[allow(dead_code)] // for ignore and auto-op lists, which aren't wired up yet
+    pub fn matches(&self, mask: &str, casemapping: Casemapping) -> bool {
+        let full = match *self {
+            Hostmask::User(ref nick, ref user, ref host) => {
+                format!("{}!{}@{}", nick, if user.is_empty() { "*" } else { user }, if host.is_empty() { "*" } else { host })
+            }
+            Hostmask::Nick(ref nick) => format!("{}!*@*", nick),
+            Hostmask::Server(ref server) => server.clone(),
+            Hostmask::None => return false
+        };
+        let mask = match *self {
+            Hostmask::Server(_) => mask.to_string(),
+            _ => Hostmask::normalize_mask(mask)
+        };
+        wildcard_match(mask.as_str(), full.as_str(), casemapping)
+    }
+
+    /// Expands `nick`, `nick!user`, `user@host` and `nick@host` to `nick!user@host` form.
+    #[allow(dead_code)]
+    pub fn normalize_mask(mask: &str) -> String {
+        let (nickuser, host) = match mask.find('@') {
+            Some(at) => (&mask[..at], &mask[at + 1..]),
+            None => (mask, "*")
+        };
+        let (nick, user) = match nickuser.find('!') {
+            Some(bang) => (&nickuser[..bang], &nickuser[bang + 1..]),
+            None if mask.contains('@') && !nickuser.is_empty() => ("*", nickuser),
+            None => (nickuser, "*")
+        };
+        let nonempty = |s: &str| if s.is_empty() { "*".to_string() } else { s.to_string() };
+        format!("{}!{}@{}", nonempty(nick), nonempty(user), nonempty(host))
+    }
+}
+
+/// Glob matching with `*` for any run of characters and `?` for exactly one,
+/// comparing characters under the given casemapping.
+#[allow(dead_code)]
+pub fn wildcard_match(pattern: &str, text: &str, casemapping: Casemapping) -> bool {
+    let pattern : Vec<char> = pattern.chars().map(|c| casemapping.fold_char(c)).collect();
+    let text : Vec<char> = text.chars().map(|c| casemapping.fold_char(c)).collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((bp, bt)) = backtrack {
+            // Let the last star swallow one more character and retry.
+            p = bp + 1;
+            t = bt + 1;
+            backtrack = Some((bp, bt + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// IRCv3 message tags, kept in the order they were received.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tags {
+    tags : Vec<(String, String)>,
+}
+
+impl Tags {
+    pub fn new() -> Tags {
+        Tags { tags: Vec::new() }
+    }
+
+    /// Parses the tag section of a line, without the leading '@'.
+    pub fn from_string(tags: &str) -> Tags {
+        let mut parsed = Tags::new();
+        for tag in tags.split(';').filter(|t| !t.is_empty()) {
+            let mut pair = tag.splitn(2, '=');
+            let key = pair.next().unwrap_or("");
+            let value = Tags::unescape(pair.next().unwrap_or(""));
+            parsed.set(key, value.as_str());
+        }
+        parsed
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Sets a tag, replacing the value of an existing tag with the same key.
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.tags.iter_mut().find(|(k, _)| k == key) {
+            Some(tag) => tag.1 = value.to_string(),
+            None => self.tags.push((key.to_string(), value.to_string()))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn escape(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                ';' => escaped.push_str("\\:"),
+                ' ' => escaped.push_str("\\s"),
+                '\\' => escaped.push_str("\\\\"),
+                '\r' => escaped.push_str("\\r"),
+                '\n' => escaped.push_str("\\n"),
+                c => escaped.push(c)
+            }
+        }
+        escaped
+    }
+
+    pub fn unescape(value: &str) -> String {
+        let mut unescaped = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            // A lone trailing backslash is dropped, unknown escapes lose the backslash.
+            match chars.next() {
+                Some(':') => unescaped.push(';'),
+                Some('s') => unescaped.push(' '),
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some(c) => unescaped.push(c),
+                None => ()
+            }
+        }
+        unescaped
+    }
+}
+
+impl fmt::Display for Tags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            if i > 0 {
+                write!(f, ";")?;
+            }
+            if value.is_empty() {
+                write!(f, "{}", key)?;
+            } else {
+                write!(f, "{}={}", key, Tags::escape(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Variants are named after the verbs as they appear on the wire.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
-    RPL_WELCOME(String),
+    Numeric(u16, Vec<String>),
     PING(String),
     PONG(String),
     USER(String, String, String, String),
     NICK(String),
     PASS(String),
-    JOIN(String),
+    JOIN(String, String),
     PART(String, String),
-    QUIT(String, String),
+    QUIT(String),
     PRIVMSG(String, String),
     NOTICE(String, String),
-    UNDEFINED,
+    MODE(String, Vec<String>),
+    TOPIC(String, Option<String>),
+    KICK(String, String, String),
+    INVITE(String, String),
+    NAMES(String, String),
+    WHO(String, String),
+    WHOIS(String, String),
+    LIST(String, String),
+    AWAY(String),
+    ISON(Vec<String>),
+    CAP(Option<String>, String, Vec<String>),
+    AUTHENTICATE(String),
+    ERROR(String),
+    KILL(String, String),
+    WALLOPS(String),
+    BATCH(String, Vec<String>),
+    Other(String, Vec<String>),
+}
+
+/// A CTCP request or reply carried in the body of a PRIVMSG or NOTICE.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ctcp {
+    pub command : String,
+    pub params : String,
+}
+
+impl Ctcp {
+    pub fn new(command: &str, params: &str) -> Ctcp {
+        Ctcp { command: command.to_uppercase(), params: params.to_string() }
+    }
+
+    /// Decodes a message body wrapped in \x01. The closing delimiter is optional,
+    /// as some clients leave it off.
+    pub fn from_text(text: &str) -> Option<Ctcp> {
+        let inner = text.strip_prefix('\x01')?;
+        let inner = inner.strip_suffix('\x01').unwrap_or(inner);
+        if inner.is_empty() {
+            return None;
+        }
+        let mut parts = inner.splitn(2, ' ');
+        let command = parts.next().unwrap_or("");
+        Some(Ctcp::new(command, parts.next().unwrap_or("")))
+    }
+
+    pub fn is_action(&self) -> bool {
+        self.command == "ACTION"
+    }
+}
+
+impl fmt::Display for Ctcp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.params.is_empty() {
+            write!(f, "\x01{}\x01", self.command)
+        } else {
+            write!(f, "\x01{} {}\x01", self.command, self.params)
+        }
+    }
+}
+
+/// How a channel mode uses its parameter: the four CHANMODES classes, plus the
+/// PREFIX modes that take a nick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModeKind {
+    List,
+    Always,
+    Set,
+    Flag,
+    Prefix,
+}
+
+/// One change out of a MODE line, e.g. `+o nick` out of `+ov-k nick1 nick2 key`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeChange {
+    pub adding : bool,
+    pub mode : char,
+    pub kind : ModeKind,
+    pub param : Option<String>,
+}
+
+impl ModeChange {
+    /// Parses the parameters of a channel MODE after the target, pairing each mode
+    /// with its parameter according to the network's CHANMODES and PREFIX.
+    pub fn parse<S: AsRef<str>>(modes: &[S], isupport: &ISupport) -> Vec<ModeChange> {
+        ModeChange::parse_with(modes, |mode| isupport.mode_kind(mode))
+    }
+
+    fn parse_with<S: AsRef<str>, F: Fn(char) -> ModeKind>(modes: &[S], kind_of: F) -> Vec<ModeChange> {
+        let mut changes = Vec::new();
+        let mut params = modes.iter().skip(1);
+        let mut adding = true;
+        for mode in modes.first().map_or("", |m| m.as_ref()).chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                mode => {
+                    let kind = kind_of(mode);
+                    let param = match kind {
+                        ModeKind::List | ModeKind::Always | ModeKind::Prefix => params.next().map(|p| p.as_ref().to_string()),
+                        ModeKind::Set if adding => params.next().map(|p| p.as_ref().to_string()),
+                        _ => None
+                    };
+                    changes.push(ModeChange { adding, mode, kind, param });
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// CAP subcommands, used to tell a server-sent target apart from the subcommand.
+const CAP_SUBCOMMANDS : [&str; 8] = ["LS", "LIST", "REQ", "ACK", "NAK", "END", "NEW", "DEL"];
+
+impl Command {
+    /// Builds a typed command from a verb and its already split parameters.
+    pub fn from_parts(verb: &str, params: Vec<String>) -> Command {
+        if verb.len() == 3 && verb.chars().all(|c| c.is_ascii_digit()) {
+            if let Ok(code) = verb.parse() {
+                return Command::Numeric(code, params);
+            }
+        }
+        let upper = verb.to_uppercase();
+        match upper.as_str() {
+            "MODE" | "ISON" | "CAP" | "BATCH" => return Command::from_list(upper.as_str(), params),
+            "WHOIS" if params.len() > 1 => {
+                let mut params = params;
+                let mask = params.pop().unwrap_or_default();
+                return Command::WHOIS(mask, params.remove(0));
+            }
+            _ => ()
+        }
+        let mut params = params.into_iter();
+        let mut next = || params.next().unwrap_or_default();
+        match upper.as_str() {
+            "PING" => Command::PING(next()),
+            "PONG" => Command::PONG(next()),
+            "USER" => {
+                let username = next();
+                let hostname = next();
+                let servername = next();
+                Command::USER(username, hostname, servername, next())
+            }
+            "NICK" => Command::NICK(next()),
+            "PASS" => Command::PASS(next()),
+            "JOIN" => {
+                let chans = next();
+                Command::JOIN(chans, next())
+            }
+            "PART" => {
+                let chan = next();
+                Command::PART(chan, next())
+            }
+            "QUIT" => Command::QUIT(next()),
+            "PRIVMSG" => {
+                let chan = next();
+                Command::PRIVMSG(chan, next())
+            }
+            "NOTICE" => {
+                let chan = next();
+                Command::NOTICE(chan, next())
+            }
+            "TOPIC" => {
+                let chan = next();
+                Command::TOPIC(chan, params.next())
+            }
+            "KICK" => {
+                let chan = next();
+                let nick = next();
+                Command::KICK(chan, nick, next())
+            }
+            "INVITE" => {
+                let nick = next();
+                Command::INVITE(nick, next())
+            }
+            "NAMES" => {
+                let chans = next();
+                Command::NAMES(chans, next())
+            }
+            "WHO" => {
+                let mask = next();
+                Command::WHO(mask, next())
+            }
+            "WHOIS" => Command::WHOIS(next(), String::new()),
+            "LIST" => {
+                let chans = next();
+                Command::LIST(chans, next())
+            }
+            "AWAY" => Command::AWAY(next()),
+            "AUTHENTICATE" => Command::AUTHENTICATE(next()),
+            "ERROR" => Command::ERROR(next()),
+            "KILL" => {
+                let nick = next();
+                Command::KILL(nick, next())
+            }
+            "WALLOPS" => Command::WALLOPS(next()),
+            _ => Command::Other(verb.to_string(), params.collect())
+        }
+    }
+
+    /// Commands whose parameters are kept as a list after a fixed head.
+    fn from_list(verb: &str, mut params: Vec<String>) -> Command {
+        match verb {
+            "MODE" => {
+                let target = if params.is_empty() { String::new() } else { params.remove(0) };
+                Command::MODE(target, params)
+            }
+            "ISON" => {
+                // Some clients send the nick list as a single trailing parameter.
+                Command::ISON(params.iter().flat_map(|p| p.split(' ')).filter(|n| !n.is_empty()).map(|n| n.to_string()).collect())
+            }
+            "CAP" => {
+                let target = if params.len() > 1 && CAP_SUBCOMMANDS.contains(&params[1].to_uppercase().as_str()) {
+                    Some(params.remove(0))
+                } else {
+                    None
+                };
+                let subcommand = if params.is_empty() { String::new() } else { params.remove(0).to_uppercase() };
+                Command::CAP(target, subcommand, params)
+            }
+            _ => {
+                let reference = if params.is_empty() { String::new() } else { params.remove(0) };
+                Command::BATCH(reference, params)
+            }
+        }
+    }
+
+    /// The verb as it appears on the wire.
+    pub fn verb(&self) -> String {
+        let verb = match *self {
+            Command::Numeric(code, _) => return format!("{:03}", code),
+            Command::PING(_) => "PING",
+            Command::PONG(_) => "PONG",
+            Command::USER(..) => "USER",
+            Command::NICK(_) => "NICK",
+            Command::PASS(_) => "PASS",
+            Command::JOIN(..) => "JOIN",
+            Command::PART(..) => "PART",
+            Command::QUIT(_) => "QUIT",
+            Command::PRIVMSG(..) => "PRIVMSG",
+            Command::NOTICE(..) => "NOTICE",
+            Command::MODE(..) => "MODE",
+            Command::TOPIC(..) => "TOPIC",
+            Command::KICK(..) => "KICK",
+            Command::INVITE(..) => "INVITE",
+            Command::NAMES(..) => "NAMES",
+            Command::WHO(..) => "WHO",
+            Command::WHOIS(..) => "WHOIS",
+            Command::LIST(..) => "LIST",
+            Command::AWAY(_) => "AWAY",
+            Command::ISON(_) => "ISON",
+            Command::CAP(..) => "CAP",
+            Command::AUTHENTICATE(_) => "AUTHENTICATE",
+            Command::ERROR(_) => "ERROR",
+            Command::KILL(..) => "KILL",
+            Command::WALLOPS(_) => "WALLOPS",
+            Command::BATCH(..) => "BATCH",
+            Command::Other(ref verb, _) => verb,
+        };
+        verb.to_string()
+    }
+
+    /// The parameter vector, in wire order.
+    pub fn params(&self) -> Vec<String> {
+        match *self {
+            Command::Numeric(_, ref params) => params.clone(),
+            Command::PING(ref param) => vec![param.clone()],
+            Command::PONG(ref param) => vec![param.clone()],
+            Command::USER(ref username, ref hostname, ref servername, ref realname) => {
+                vec![username.clone(), hostname.clone(), servername.clone(), realname.clone()]
+            }
+            Command::NICK(ref nick) => vec![nick.clone()],
+            Command::PASS(ref pass) => vec![pass.clone()],
+            Command::JOIN(ref chans, ref keys) => vec![chans.clone(), keys.clone()],
+            Command::PART(ref chan, ref message) => vec![chan.clone(), message.clone()],
+            Command::QUIT(ref message) => vec![message.clone()],
+            Command::PRIVMSG(ref chan, ref message) => vec![chan.clone(), message.clone()],
+            Command::NOTICE(ref chan, ref message) => vec![chan.clone(), message.clone()],
+            Command::MODE(ref target, ref modes) => {
+                let mut params = vec![target.clone()];
+                params.extend(modes.iter().cloned());
+                params
+            }
+            Command::TOPIC(ref chan, ref topic) => {
+                let mut params = vec![chan.clone()];
+                params.extend(topic.iter().cloned());
+                params
+            }
+            Command::KICK(ref chan, ref nick, ref comment) => vec![chan.clone(), nick.clone(), comment.clone()],
+            Command::INVITE(ref nick, ref chan) => vec![nick.clone(), chan.clone()],
+            Command::NAMES(ref chans, ref target) => vec![chans.clone(), target.clone()],
+            Command::WHO(ref mask, ref flags) => vec![mask.clone(), flags.clone()],
+            Command::WHOIS(ref mask, ref server) => {
+                if server.is_empty() {
+                    vec![mask.clone()]
+                } else {
+                    vec![server.clone(), mask.clone()]
+                }
+            }
+            Command::LIST(ref chans, ref target) => vec![chans.clone(), target.clone()],
+            Command::AWAY(ref message) => vec![message.clone()],
+            Command::ISON(ref nicks) => nicks.clone(),
+            Command::CAP(ref target, ref subcommand, ref rest) => {
+                let mut params : Vec<String> = target.iter().cloned().collect();
+                params.push(subcommand.clone());
+                params.extend(rest.iter().cloned());
+                params
+            }
+            Command::AUTHENTICATE(ref data) => vec![data.clone()],
+            Command::ERROR(ref message) => vec![message.clone()],
+            Command::KILL(ref nick, ref comment) => vec![nick.clone(), comment.clone()],
+            Command::WALLOPS(ref text) => vec![text.clone()],
+            Command::BATCH(ref reference, ref rest) => {
+                let mut params = vec![reference.clone()];
+                params.extend(rest.iter().cloned());
+                params
+            }
+            Command::Other(_, ref params) => params.clone(),
+        }
+    }
+
+    /// How many parameters at the end may be left off the line when they are empty.
+    fn optional(&self) -> usize {
+        match *self {
+            Command::NAMES(..) | Command::WHO(..) | Command::LIST(..) => 2,
+            Command::JOIN(..) | Command::PART(..) | Command::QUIT(_) | Command::PING(_) |
+            Command::PONG(_) | Command::KICK(..) | Command::AWAY(_) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Whether the last parameter is free text and is written in trailing form.
+    fn trailing(&self) -> bool {
+        match *self {
+            Command::Numeric(..) | Command::USER(..) | Command::PART(..) | Command::QUIT(_) |
+            Command::PRIVMSG(..) | Command::NOTICE(..) | Command::KICK(..) | Command::AWAY(_) |
+            Command::ERROR(_) | Command::KILL(..) | Command::WALLOPS(_) => true,
+            Command::TOPIC(_, ref topic) => topic.is_some(),
+            Command::CAP(ref target, ref subcommand, ref rest) => {
+                !rest.is_empty() && match subcommand.as_str() {
+                    "REQ" | "ACK" | "NAK" | "NEW" | "DEL" => true,
+                    "LS" | "LIST" => target.is_some(),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The most bytes a line may take, including the CRLF but not any message tags.
+pub const MAX_LINE_LEN : usize = 512;
+
+/// Splits text into pieces of at most `max` bytes, preferring to break at spaces
+/// and never breaking inside a UTF-8 sequence.
+pub fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max {
+        let mut cut = max;
+        while cut > 0 && !rest.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        if cut == 0 {
+            // Always make progress, even if a single character is over budget.
+            cut = rest.char_indices().nth(1).map_or(rest.len(), |(i, _)| i);
+        }
+        // A space right after the budget is as good a break as one inside it.
+        let space = if rest.as_bytes().get(cut) == Some(&b' ') { Some(cut) } else { rest[..cut].rfind(' ') };
+        match space {
+            Some(space) if space > 0 => {
+                pieces.push(&rest[..space]);
+                rest = &rest[space + 1..];
+            }
+            _ => {
+                pieces.push(&rest[..cut]);
+                rest = &rest[cut..];
+            }
+        }
+    }
+    // Breaking at a final space leaves nothing over, and an empty piece can't be sent.
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+/// Splits a raw line into its tags, prefix, verb and parameters, in that order, without
+/// allocating. The flag is set when the last parameter was given in trailing form.
+fn split_line(line: &str) -> (Option<&str>, Option<&str>, &str, Vec<&str>, bool) {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    let tags = if rest.starts_with('@') {
+        let end = rest.find(' ').unwrap_or(rest.len());
+        let tags = &rest[1..end];
+        rest = rest[end..].trim_start_matches(' ');
+        Some(tags)
+    } else {
+        None
+    };
+    let prefix = if rest.starts_with(':') {
+        let end = rest.find(' ').unwrap_or(rest.len());
+        let prefix = &rest[1..end];
+        rest = &rest[end..];
+        Some(prefix)
+    } else {
+        None
+    };
+    rest = rest.trim_start_matches(' ');
+    let end = rest.find(' ').unwrap_or(rest.len());
+    let verb = &rest[..end];
+    rest = &rest[end..];
+
+    let mut params = Vec::new();
+    let mut trailing = false;
+    loop {
+        rest = rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(text) = rest.strip_prefix(':') {
+            params.push(text);
+            trailing = true;
+            break;
+        }
+        let end = rest.find(' ').unwrap_or(rest.len());
+        params.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    (tags, prefix, verb, params, trailing)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
+    pub tags : Tags,
     pub hostmask : Hostmask,
     pub command : Command,
-    raw : String
+    trailing : bool,
 }
 
 impl Message {
-    pub fn rpl_welcome(hostmask: Hostmask, param: &str) -> Message {
-        let mut raw = format!(":{} 001 {}", hostmask, param);
-        if hostmask == Hostmask::None {
-            raw = format!("001 {}", param);
-        }
-        Message { hostmask: hostmask, command: Command::RPL_WELCOME(param.to_string()), raw: raw.to_string() }
+    pub fn new(hostmask: Hostmask, command: Command) -> Message {
+        let trailing = command.trailing();
+        Message { tags: Tags::new(), hostmask, command, trailing }
+    }
+
+    pub fn numeric(hostmask: Hostmask, code: u16, params: &[&str]) -> Message {
+        Message::new(hostmask, Command::Numeric(code, params.iter().map(|p| p.to_string()).collect()))
+    }
+
+    pub fn rpl_welcome(hostmask: Hostmask, target: &str, text: &str) -> Message {
+        Message::numeric(hostmask, numeric::RPL_WELCOME, &[target, text])
     }
 
     pub fn ping(hostmask: Hostmask, param: &str) -> Message {
-        let mut raw = format!(":{} PING {}", hostmask, param);
-        if hostmask == Hostmask::None {
-            raw = format!("PING {}", param);
-        }
-        Message { hostmask: hostmask, command: Command::PING(param.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::PING(param.to_string()))
     }
 
     pub fn pong(hostmask: Hostmask, param: &str) -> Message {
-        let mut raw = format!(":{} PONG {}", hostmask, param);
-        if hostmask == Hostmask::None {
-            raw = format!("PONG {}", param);
-        }
-        Message { hostmask: hostmask, command: Command::PONG(param.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::PONG(param.to_string()))
     }
 
     pub fn user(hostmask: Hostmask, username: &str, realname: &str) -> Message {
         let hostname = "*";
         let servername = "0";
-        let mut raw = format!(":{} USER {} {} {} :{}", hostmask, username, hostname, servername, realname);
-        if hostmask == Hostmask::None {
-            raw = format!("USER {} {} {} :{}", username, hostname, servername, realname);
-        }
-        Message { hostmask: hostmask, command: Command::USER(username.to_string(), hostname.to_string(), servername.to_string(), realname.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::USER(username.to_string(), hostname.to_string(), servername.to_string(), realname.to_string()))
     }
 
     pub fn nick(hostmask: Hostmask, nickname: &str) -> Message {
-        let mut raw = format!(":{} NICK {}", hostmask, nickname);
-        if hostmask == Hostmask::None {
-            raw = format!("NICK {}", nickname);
-        }
-        Message { hostmask: hostmask, command: Command::NICK(nickname.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::NICK(nickname.to_string()))
     }
 
     pub fn pass(hostmask: Hostmask, password: &str) -> Message {
-        let mut raw = format!(":{} PASS {}", hostmask, password);
-        if hostmask == Hostmask::None {
-            raw = format!("PASS {}", password);
-        }
-        Message { hostmask: hostmask, command: Command::PASS(password.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::PASS(password.to_string()))
     }
 
     pub fn join(hostmask: Hostmask, chan: &str) -> Message {
-        let mut raw = format!(":{} JOIN {}", hostmask, chan);
-        if hostmask == Hostmask::None {
-            raw = format!("JOIN {}", chan);
-        }
-        Message { hostmask: hostmask, command: Command::JOIN(chan.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::JOIN(chan.to_string(), "".to_string()))
+    }
+
+    /// Joins several channels at once; keyed channels must come first, in the
+    /// same order as their keys.
+    pub fn join_keys(hostmask: Hostmask, chans: &str, keys: &str) -> Message {
+        Message::new(hostmask, Command::JOIN(chans.to_string(), keys.to_string()))
     }
 
     pub fn part(hostmask: Hostmask, chan: &str, message: &str) -> Message {
-        let mut raw = format!(":{} PART {} :{}", hostmask, chan, message);
-        if hostmask == Hostmask::None {
-            raw = format!("PART {} :{}", chan, message);
-        }
-        Message { hostmask: hostmask, command: Command::PART(chan.to_string(), message.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::PART(chan.to_string(), message.to_string()))
     }
 
-    pub fn quit(hostmask: Hostmask, chan: &str, message: &str) -> Message {
-        let mut raw = format!(":{} QUIT {} :{}", hostmask, chan, message);
-        if hostmask == Hostmask::None {
-            raw = format!("QUIT {} :{}", chan, message);
-        }
-        Message { hostmask: hostmask, command: Command::QUIT(chan.to_string(), message.to_string()), raw: raw.to_string() }
+    pub fn quit(hostmask: Hostmask, message: &str) -> Message {
+        Message::new(hostmask, Command::QUIT(message.to_string()))
     }
 
     pub fn privmsg(hostmask: Hostmask, chan: &str, message: &str) -> Message {
-        let mut raw = format!(":{} PRIVMSG {} :{}", hostmask, chan, message);
-        if hostmask == Hostmask::None {
-            raw = format!("PRIVMSG {} :{}", chan, message);
-        }
-        Message { hostmask: hostmask, command: Command::PRIVMSG(chan.to_string(), message.to_string()), raw: raw.to_string() }
+        Message::new(hostmask, Command::PRIVMSG(chan.to_string(), message.to_string()))
     }
 
     pub fn notice(hostmask: Hostmask, chan: &str, message: &str) -> Message {
-        let mut raw = format!(":{} NOTICE {} :{}", hostmask, chan, message);
-        if hostmask == Hostmask::None {
-            raw = format!("NOTICE {} :{}", chan, message);
+        Message::new(hostmask, Command::NOTICE(chan.to_string(), message.to_string()))
+    }
+
+    pub fn mode(hostmask: Hostmask, target: &str, modes: &[&str]) -> Message {
+        Message::new(hostmask, Command::MODE(target.to_string(), modes.iter().map(|m| m.to_string()).collect()))
+    }
+
+    pub fn ison(hostmask: Hostmask, nicks: &[&str]) -> Message {
+        Message::new(hostmask, Command::ISON(nicks.iter().map(|n| n.to_string()).collect()))
+    }
+
+    pub fn cap(hostmask: Hostmask, target: Option<&str>, subcommand: &str, params: &[&str]) -> Message {
+        Message::new(hostmask, Command::CAP(target.map(|t| t.to_string()), subcommand.to_string(), params.iter().map(|p| p.to_string()).collect()))
+    }
+
+    pub fn authenticate(hostmask: Hostmask, data: &str) -> Message {
+        Message::new(hostmask, Command::AUTHENTICATE(data.to_string()))
+    }
+
+    pub fn other(hostmask: Hostmask, verb: &str, params: &[&str]) -> Message {
+        Message::new(hostmask, Command::Other(verb.to_string(), params.iter().map(|p| p.to_string()).collect()))
+    }
+
+    /// A CTCP reply, sent as a NOTICE.
+    pub fn ctcp_reply(hostmask: Hostmask, target: &str, command: &str, params: &str) -> Message {
+        Message::notice(hostmask, target, Ctcp::new(command, params).to_string().as_str())
+    }
+
+    /// Splits a PRIVMSG or NOTICE that would not fit in a single line. `relay_prefix` is
+    /// the length of the prefix a server will put on the message when relaying it, for
+    /// messages that do not carry one yet. Tags have their own budget and are copied
+    /// onto every piece; CTCP messages are re-wrapped piece by piece.
+    pub fn split(&self, relay_prefix: usize) -> Vec<Message> {
+        let (target, text) = match self.command {
+            Command::PRIVMSG(ref target, ref text) | Command::NOTICE(ref target, ref text) => (target, text),
+            _ => return vec![self.clone()]
+        };
+        let prefix = match self.hostmask {
+            Hostmask::None => relay_prefix,
+            ref hostmask => hostmask.to_string().len()
+        };
+        // ":prefix VERB target :text\r\n"
+        let overhead = prefix + 2 + self.command.verb().len() + 1 + target.len() + 2 + 2;
+        let budget = MAX_LINE_LEN.saturating_sub(overhead);
+        let ctcp = self.get_ctcp();
+        let pieces = match ctcp {
+            Some(ref ctcp) => split_text(ctcp.params.as_str(), budget.saturating_sub(ctcp.command.len() + 3)),
+            None => split_text(text, budget)
+        };
+        if pieces.len() < 2 {
+            return vec![self.clone()];
         }
-        Message { hostmask: hostmask, command: Command::NOTICE(chan.to_string(), message.to_string()), raw: raw.to_string() }
+        pieces.into_iter().map(|piece| {
+            let piece = match ctcp {
+                Some(ref ctcp) => Ctcp::new(ctcp.command.as_str(), piece).to_string(),
+                None => piece.to_string()
+            };
+            let command = match self.command {
+                Command::PRIVMSG(..) => Command::PRIVMSG(target.clone(), piece),
+                _ => Command::NOTICE(target.clone(), piece)
+            };
+            Message { tags: self.tags.clone(), hostmask: self.hostmask.clone(), command, trailing: true }
+        }).collect()
     }
 
-    pub fn read_hostmask(hostmask: &str) -> Hostmask {
-        let sections : Vec<&str> = hostmask.split(|c| c == '!' || c == '@').collect();
-        if sections.len() == 3 {
-            Hostmask::User(sections[0].to_string(), sections[1].to_string(), sections[2].to_string())
-        } else {
-            Hostmask::Server(sections[0].to_string())
+    /// The CTCP payload of a PRIVMSG or NOTICE, if it carries one.
+    pub fn get_ctcp(&self) -> Option<Ctcp> {
+        match self.command {
+            Command::PRIVMSG(_, ref text) | Command::NOTICE(_, ref text) => Ctcp::from_text(text),
+            _ => None
         }
     }
 
     pub fn from_string(line: &str) -> Message {
-        let mut words = line.split_whitespace();
-        let hostmask = if line.chars().next() == Some(':') {
-            let sections : Vec<&str> = words.next().unwrap().split(|c| c == '!' || c == '@').collect();
-            if sections.len() == 3 {
-                Hostmask::User(sections[0].to_string().split_off(1), sections[1].to_string(), sections[2].to_string())
+        MessageRef::parse(line).to_message()
+    }
+}
+
+// Constructors for the rest of the command set, which the bouncer doesn't send yet.
+#[allow(dead_code)]
+impl Message {
+    pub fn topic(hostmask: Hostmask, chan: &str, topic: Option<&str>) -> Message {
+        Message::new(hostmask, Command::TOPIC(chan.to_string(), topic.map(|t| t.to_string())))
+    }
+
+    pub fn kick(hostmask: Hostmask, chan: &str, nick: &str, comment: &str) -> Message {
+        Message::new(hostmask, Command::KICK(chan.to_string(), nick.to_string(), comment.to_string()))
+    }
+
+    pub fn invite(hostmask: Hostmask, nick: &str, chan: &str) -> Message {
+        Message::new(hostmask, Command::INVITE(nick.to_string(), chan.to_string()))
+    }
+
+    pub fn names(hostmask: Hostmask, chans: &str) -> Message {
+        Message::new(hostmask, Command::NAMES(chans.to_string(), String::new()))
+    }
+
+    pub fn who(hostmask: Hostmask, mask: &str) -> Message {
+        Message::new(hostmask, Command::WHO(mask.to_string(), String::new()))
+    }
+
+    pub fn whois(hostmask: Hostmask, mask: &str) -> Message {
+        Message::new(hostmask, Command::WHOIS(mask.to_string(), String::new()))
+    }
+
+    pub fn list(hostmask: Hostmask, chans: &str) -> Message {
+        Message::new(hostmask, Command::LIST(chans.to_string(), String::new()))
+    }
+
+    pub fn away(hostmask: Hostmask, message: &str) -> Message {
+        Message::new(hostmask, Command::AWAY(message.to_string()))
+    }
+
+    pub fn error(hostmask: Hostmask, message: &str) -> Message {
+        Message::new(hostmask, Command::ERROR(message.to_string()))
+    }
+
+    pub fn kill(hostmask: Hostmask, nick: &str, comment: &str) -> Message {
+        Message::new(hostmask, Command::KILL(nick.to_string(), comment.to_string()))
+    }
+
+    pub fn wallops(hostmask: Hostmask, text: &str) -> Message {
+        Message::new(hostmask, Command::WALLOPS(text.to_string()))
+    }
+
+    pub fn batch(hostmask: Hostmask, reference: &str, params: &[&str]) -> Message {
+        Message::new(hostmask, Command::BATCH(reference.to_string(), params.iter().map(|p| p.to_string()).collect()))
+    }
+
+    /// A CTCP request, sent as a PRIVMSG.
+    pub fn ctcp(hostmask: Hostmask, target: &str, command: &str, params: &str) -> Message {
+        Message::privmsg(hostmask, target, Ctcp::new(command, params).to_string().as_str())
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.tags.is_empty() {
+            write!(f, "@{} ", self.tags)?;
+        }
+        if self.hostmask != Hostmask::None {
+            write!(f, ":{} ", self.hostmask)?;
+        }
+        write!(f, "{}", self.command.verb())?;
+        let mut params = self.command.params();
+        if !self.trailing {
+            for _ in 0..self.command.optional() {
+                if params.last().is_some_and(|p| p.is_empty()) {
+                    params.pop();
+                }
+            }
+        }
+        if let Some((last, middle)) = params.split_last() {
+            for param in middle {
+                write!(f, " {}", param)?;
+            }
+            if self.trailing || last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
             } else {
-                Hostmask::Server(sections[0].to_string().split_off(1))
+                write!(f, " {}", last)?;
             }
+        }
+        Ok(())
+    }
+}
+
+/// A message borrowed from the line it was parsed from. Parsing allocates only the
+/// parameter vector, so it suits lines that are inspected and then relayed or dropped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageRef<'a> {
+    pub tags : Option<&'a str>,
+    pub prefix : Option<&'a str>,
+    pub verb : &'a str,
+    pub params : Vec<&'a str>,
+    trailing : bool,
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn parse(line: &'a str) -> MessageRef<'a> {
+        let (tags, prefix, verb, params, trailing) = split_line(line);
+        MessageRef { tags, prefix, verb, params, trailing }
+    }
+
+    /// The verb in upper case; only allocates for the rare lower case verb.
+    pub fn verb(&self) -> Cow<'a, str> {
+        if self.verb.bytes().any(|b| b.is_ascii_lowercase()) {
+            Cow::Owned(self.verb.to_ascii_uppercase())
         } else {
-            Hostmask::None
-        };
-        let command = match words.next() {
-            Some("001") => {
-                let params = words.collect::<Vec<&str>>().join(" ");
-                Command::RPL_WELCOME(params.to_string())
-            }
-            Some("PING") => {
-                let param = words.collect::<Vec<&str>>().join(" ");
-                Command::PING(param.to_string())
-            }
-            Some("PONG") => {
-                let param = words.collect::<Vec<&str>>().join(" ");
-                Command::PONG(param.to_string())
-            }
-            Some("USER") => {
-                let username = words.next().unwrap_or("");
-                let hostname = words.next().unwrap_or("");
-                let servername = words.next().unwrap_or("");
-                let rest = words.collect::<Vec<&str>>().join(" ");
-                let realname = rest.splitn(2, ':').collect::<Vec<&str>>().pop().unwrap_or("");
-                Command::USER(username.to_string(), hostname.to_string(), servername.to_string(), realname.to_string())
-            }
-            Some("NICK") => {
-                let nick = words.next().unwrap_or("");
-                Command::NICK(nick.to_string())
-            }
-            Some("PASS") => {
-                let pass = words.next().unwrap_or("");
-                Command::PASS(pass.to_string())
-            }
-            Some("JOIN") => {
-                let chan = words.next().unwrap_or("");
-                Command::JOIN(chan.to_string())
-            }
-            Some("PART") => {
-                let chan = words.next().unwrap_or("");
-                let rest = words.collect::<Vec<&str>>().join(" ");
-                let msg = rest.splitn(2, ':').collect::<Vec<&str>>().pop().unwrap_or("");
-                Command::PART(chan.to_string(), msg.to_string())
-            }
-            Some("QUIT") => {
-                let chan = words.next().unwrap_or("");
-                let rest = words.collect::<Vec<&str>>().join(" ");
-                let msg = rest.splitn(2, ':').collect::<Vec<&str>>().pop().unwrap_or("");
-                Command::QUIT(chan.to_string(), msg.to_string())
-            }
-            Some("PRIVMSG") => {
-                let chan = words.next().unwrap_or("");
-                let rest = words.collect::<Vec<&str>>().join(" ");
-                let msg = rest.splitn(2, ':').collect::<Vec<&str>>().pop().unwrap_or("");
-                Command::PRIVMSG(chan.to_string(), msg.to_string())
-            }
-            Some("NOTICE") => {
-                let chan = words.next().unwrap_or("");
-                let rest = words.collect::<Vec<&str>>().join(" ");
-                let msg = rest.splitn(2, ':').collect::<Vec<&str>>().pop().unwrap_or("");
-                Command::NOTICE(chan.to_string(), msg.to_string())
-            }
-            _ => Command::UNDEFINED
+            Cow::Borrowed(self.verb)
+        }
+    }
+
+    pub fn numeric(&self) -> Option<u16> {
+        if self.verb.len() == 3 && self.verb.bytes().all(|b| b.is_ascii_digit()) {
+            self.verb.parse().ok()
+        } else {
+            None
+        }
+    }
+
+    /// A parameter by position, or the empty string if there are not that many.
+    pub fn param(&self, index: usize) -> &'a str {
+        self.params.get(index).cloned().unwrap_or("")
+    }
+
+    /// The nick of a user prefix, without allocating.
+    pub fn nick(&self) -> Option<&'a str> {
+        let prefix = self.prefix?;
+        match prefix.find(['!', '@']) {
+            Some(pos) => Some(&prefix[..pos]),
+            None if !prefix.is_empty() && !prefix.contains('.') => Some(prefix),
+            None => None
+        }
+    }
+
+    pub fn hostmask(&self) -> Hostmask {
+        match self.prefix {
+            Some(prefix) => Hostmask::from_string(prefix),
+            None => Hostmask::None
+        }
+    }
+
+    /// The CTCP payload of a PRIVMSG or NOTICE, if it carries one.
+    pub fn get_ctcp(&self) -> Option<Ctcp> {
+        match &*self.verb() {
+            "PRIVMSG" | "NOTICE" => Ctcp::from_text(self.param(1)),
+            _ => None
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        let tags = match self.tags {
+            Some(tags) => Tags::from_string(tags),
+            None => Tags::new()
         };
-        Message { hostmask: hostmask, command: command, raw: line.to_string() }
+        let params = self.params.iter().map(|p| p.to_string()).collect();
+        Message { tags, hostmask: self.hostmask(), command: Command::from_parts(self.verb, params), trailing: self.trailing }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(line: &str) {
+        assert_eq!(Message::from_string(line).to_string(), line);
+    }
+
+    #[test]
+    fn round_trips_lines() {
+        round_trip(":nick!user@host PRIVMSG #chan :hello  world: with  spaces");
+        round_trip(":srv 005 me CHANTYPES=# PREFIX=(ov)@+ :are supported by this server");
+        round_trip(":op!u@h KICK #chan victim :reason: text");
+        round_trip(":srv MODE #chan +ov-k nick1 nick2 key");
+        round_trip("PING :irc.example.net");
+        round_trip("PING irc.example.net");
+        round_trip("JOIN #a,#b key1");
+        round_trip("JOIN #a");
+        round_trip("FOO bar :baz qux");
+    }
+
+    #[test]
+    fn keeps_middle_and_trailing_params() {
+        let msg = Message::from_string(":a!b@c PRIVMSG #chan :a :b  c");
+        assert_eq!(msg.command, Command::PRIVMSG("#chan".to_string(), "a :b  c".to_string()));
+        let msg = Message::from_string("JOIN #a,#b key1,key2");
+        assert_eq!(msg.command, Command::JOIN("#a,#b".to_string(), "key1,key2".to_string()));
+        let msg = Message::from_string("FOO bar :baz qux");
+        assert_eq!(msg.command, Command::Other("FOO".to_string(), vec!["bar".to_string(), "baz qux".to_string()]));
+    }
+
+    #[test]
+    fn builds_lines() {
+        assert_eq!(Message::join(Hostmask::None, "#a").to_string(), "JOIN #a");
+        assert_eq!(Message::join_keys(Hostmask::None, "#a,#b", "k").to_string(), "JOIN #a,#b k");
+        assert_eq!(Message::privmsg(Hostmask::None, "#a", ":)").to_string(), "PRIVMSG #a ::)");
     }
 
-    pub fn to_string(&self) -> String {
-        self.raw.clone()
+    #[test]
+    fn escapes_tag_values() {
+        assert_eq!(Tags::escape("a;b c\\d\r\n"), "a\\:b\\sc\\\\d\\r\\n");
+        assert_eq!(Tags::unescape("a\\:b\\sc\\\\d\\r\\n"), "a;b c\\d\r\n");
+        assert_eq!(Tags::unescape("trailing\\"), "trailing");
+        assert_eq!(Tags::unescape("\\x"), "x");
+    }
+
+    #[test]
+    fn round_trips_tags() {
+        let line = "@time=2020-01-01T00:00:00.000Z;msgid=abc;+example=a\\sb;flag :nick!u@h PRIVMSG #c :hi";
+        let msg = Message::from_string(line);
+        assert_eq!(msg.tags.get("+example"), Some("a b"));
+        assert_eq!(msg.tags.get("flag"), Some(""));
+        assert_eq!(msg.hostmask, Hostmask::User("nick".to_string(), "u".to_string(), "h".to_string()));
+        assert_eq!(msg.to_string(), line);
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        assert_eq!(Hostmask::from_string("nick@host"), Hostmask::User("nick".to_string(), "".to_string(), "host".to_string()));
+        assert_eq!(Hostmask::from_string("nick!user"), Hostmask::User("nick".to_string(), "user".to_string(), "".to_string()));
+        assert_eq!(Hostmask::from_string("nick"), Hostmask::Nick("nick".to_string()));
+        assert_eq!(Hostmask::from_string("irc.example.net"), Hostmask::Server("irc.example.net".to_string()));
+        assert_eq!(Hostmask::from_string("n!u@2001:db8::1").to_string(), "n!u@2001:db8::1");
+    }
+
+    #[test]
+    fn normalizes_masks() {
+        assert_eq!(Hostmask::normalize_mask("nick"), "nick!*@*");
+        assert_eq!(Hostmask::normalize_mask("nick!user"), "nick!user@*");
+        assert_eq!(Hostmask::normalize_mask("user@host"), "*!user@host");
+        assert_eq!(Hostmask::normalize_mask("!@"), "*!*@*");
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let rfc = Casemapping::Rfc1459;
+        assert!(wildcard_match("a*b?c", "aXXbYc", rfc));
+        assert!(wildcard_match("*", "", rfc));
+        assert!(!wildcard_match("a*", "", rfc));
+        assert!(!wildcard_match("a?c", "ac", rfc));
+        assert!(wildcard_match("*a*a*", "banana", rfc));
+        assert!(wildcard_match("[foo]", "{FOO}", rfc));
+        assert!(!wildcard_match("[foo]", "{FOO}", Casemapping::Ascii));
+
+        let user = Hostmask::User("Nick".to_string(), "user".to_string(), "host.example".to_string());
+        assert!(user.matches("nick!*@*.example", rfc));
+        assert!(user.matches("nick", rfc));
+        assert!(user.matches("*@host.example", rfc));
+        assert!(!user.matches("*!*@other.example", rfc));
+        assert!(!Hostmask::None.matches("*", rfc));
+    }
+
+    #[test]
+    fn splits_text() {
+        assert_eq!(split_text("hello world foo", 11), vec!["hello world", "foo"]);
+        assert_eq!(split_text("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(split_text("\u{e9}\u{e9}\u{e9}", 3), vec!["\u{e9}", "\u{e9}", "\u{e9}"]);
+        assert_eq!(split_text("\u{1f600}x", 2), vec!["\u{1f600}", "x"]);
+        assert_eq!(split_text("short", 10), vec!["short"]);
+        assert_eq!(split_text("hello world ", 11), vec!["hello world"]);
+        assert_eq!(split_text("", 10), vec![""]);
+    }
+
+    #[test]
+    fn splits_long_messages() {
+        let text = "word ".repeat(300);
+        let text = text.trim_end();
+        let relay_prefix = 60;
+        let pieces = Message::privmsg(Hostmask::None, "#chan", text).split(relay_prefix);
+        assert!(pieces.len() > 1);
+        for piece in &pieces {
+            // ":prefix " and CRLF come on top of what we send.
+            assert!(piece.to_string().len() + relay_prefix + 2 + 2 <= MAX_LINE_LEN);
+        }
+        let texts : Vec<String> = pieces.iter().map(|p| p.command.params()[1].clone()).collect();
+        assert_eq!(texts.join(" "), text);
+    }
+
+    #[test]
+    fn splits_with_tags_and_ctcp() {
+        let line = format!("@+draft/reply=abc :n!u@h PRIVMSG #chan :\x01ACTION {}\x01", "\u{e9}t\u{e9} ".repeat(200));
+        let msg = Message::from_string(line.as_str());
+        let pieces = msg.split(0);
+        assert!(pieces.len() > 1);
+        // "@tags " has its own budget, the CRLF counts against the line.
+        let tags = msg.tags.to_string().len() + 2;
+        for piece in &pieces {
+            assert_eq!(piece.tags, msg.tags);
+            assert!(piece.to_string().len() - tags + 2 <= MAX_LINE_LEN);
+            let ctcp = piece.get_ctcp().unwrap();
+            assert!(ctcp.is_action());
+            assert!(piece.command.params()[1].ends_with('\x01'));
+        }
     }
 }
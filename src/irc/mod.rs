@@ -1,31 +1,193 @@
 
-use std::io::{self, BufReader, Lines};
-use std::iter::FilterMap;
-use std::net::TcpStream;
+use std::io;
+use irc::codec::LineReader;
+use irc::stream::Stream;
 
+pub mod casemap;
+pub mod codec;
+pub mod isupport;
 pub mod message;
+pub mod numeric;
 pub mod server;
+pub mod stream;
 pub mod client;
 
+// How many made-up nicks to try after the alternates before giving up.
+const NICK_SUFFIXES : usize = 5;
+
 #[derive(Clone)]
 pub struct Config {
     pub name : String,
     pub nick : String,
+    pub alt_nicks : Vec<String>,
+    pub username : String,
+    pub realname : String,
     pub host : String,
     pub port : u16,
     pub pass : String,
+    pub sasl : Option<Sasl>,
+    pub nickserv : Option<NickServ>,
     pub ssl : bool,
+    pub ca_file : Option<String>,
+    pub client_cert : Option<String>,
+    pub client_key : Option<String>,
+    pub fingerprints : Vec<String>,
+    pub tofu : bool,
+    pub verify : bool,
+    pub ctcp_replies : bool,
+    pub caps : Vec<String>,
+    pub encoding : String,
+    pub fallback_encoding : String,
     pub chans : Vec<String>,
 }
 
+/// SASL credentials for a network. With `required`, a failed login, or a
+/// server without SASL, drops the connection rather than carrying on
+/// unauthenticated, and refused credentials aren't tried again.
+#[derive(Clone)]
+pub struct Sasl {
+    pub mechanism : String,
+    pub user : String,
+    pub password : String,
+    pub required : bool,
+}
+
+/// Services login for networks without SASL. The command templates are sent
+/// to `service` with `{nick}`, `{account}` and `{password}` filled in, and an
+/// empty `ghost` template turns ghosting off.
+#[derive(Clone)]
+pub struct NickServ {
+    pub service : String,
+    pub account : String,
+    pub password : String,
+    pub identify : String,
+    pub ghost : String,
+    pub delay_join : bool,
+}
+
+impl NickServ {
+    pub fn command(&self, template: &str, nick: &str) -> String {
+        template.replace("{nick}", nick)
+            .replace("{account}", self.account.as_str())
+            .replace("{password}", self.password.as_str())
+    }
+}
+
+impl Config {
+    /// The nick to try on the given attempt at registering: the configured nick,
+    /// then each alternate, then the nick with an underscore, then with its
+    /// last character replaced by a digit. Servers may cut nicks short without
+    /// saying so, so the digit goes no further in than the ninth character,
+    /// which every server allows. `None` once those run out.
+    pub fn nick_candidate(&self, attempt: usize) -> Option<String> {
+        if attempt == 0 {
+            return Some(self.nick.clone());
+        }
+        if let Some(alt) = self.alt_nicks.get(attempt - 1) {
+            return Some(alt.clone());
+        }
+        match attempt - self.alt_nicks.len() {
+            1 => Some(format!("{}_", self.nick)),
+            n if n <= NICK_SUFFIXES => {
+                let keep = self.nick.chars().count().clamp(2, 9) - 1;
+                let head : String = self.nick.chars().take(keep).collect();
+                Some(format!("{}{}", head, n - 1))
+            }
+            _ => None
+        }
+    }
+
+    /// Checks what we send while registering, since servers drop or misparse
+    /// registration lines with stray spaces or line breaks in them, and turns
+    /// away settings that could never log in.
+    pub fn validate(&self) -> Result<(), String> {
+        for nick in std::iter::once(&self.nick).chain(self.alt_nicks.iter()) {
+            if !valid_nick(nick) {
+                return Err(format!("invalid nick {:?}", nick));
+            }
+        }
+        if self.username.is_empty() || self.username.chars().any(|c| c == ' ' || c == '@' || c.is_control()) {
+            return Err(format!("invalid username {:?}", self.username));
+        }
+        if self.realname.is_empty() || self.realname.chars().any(|c| c == '\r' || c == '\n' || c == '\0') {
+            return Err(format!("invalid realname {:?}", self.realname));
+        }
+        if self.pass.chars().any(|c| c.is_control()) {
+            return Err("invalid pass".to_string());
+        }
+        // EXTERNAL logs in with the client certificate, so without one it can only fail.
+        if self.sasl.as_ref().is_some_and(|s| s.mechanism == "external") && (!self.ssl || self.client_cert.is_none()) {
+            return Err("sasl external needs ssl and a client_cert".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn valid_nick(nick: &str) -> bool {
+    match nick.chars().next() {
+        None | Some('$') | Some(':') | Some('#') | Some('&') | Some('-') => false,
+        Some(c) if c.is_ascii_digit() => false,
+        _ => !nick.chars().any(|c| " ,*?!@.".contains(c) || c.is_control())
+    }
+}
+
 pub trait Connection {
     fn id(&self) -> usize;
     fn name(&self) -> String;
     fn is_connected(&self) -> bool;
     fn set_connected(&mut self, value: bool);
-    fn try_ping(&mut self) -> bool;
-    fn register_pong(&mut self);
+    /// A line we send, as it may appear in the log.
+    fn loggable(&self, line: &str) -> String {
+        line.to_string()
+    }
     fn send(&mut self, string: String) -> io::Result<()>;
-    fn read(&mut self) -> io::Result<FilterMap<Lines<BufReader<TcpStream>>, fn(io::Result<String>) -> Option<String>>>;
+    fn read(&mut self) -> io::Result<LineReader<Stream>>;
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(nick: &str, alt_nicks: &[&str]) -> Config {
+        Config { name: "net".to_string(), nick: nick.to_string(), alt_nicks: alt_nicks.iter().map(|n| n.to_string()).collect(), username: nick.to_string(), realname: "carbon".to_string(), host: "irc.example.org".to_string(), port: 6667, pass: "".to_string(), sasl: None, nickserv: None, ssl: false, ca_file: None, client_cert: None, client_key: None, fingerprints: Vec::new(), tofu: false, verify: true, ctcp_replies: true, caps: Vec::new(), encoding: "utf-8".to_string(), fallback_encoding: "windows-1252".to_string(), chans: Vec::new() }
+    }
+
+    #[test]
+    fn tries_alternates_then_suffixes() {
+        let cfg = config("alice", &["alicia"]);
+        let candidates : Vec<String> = (0..).map_while(|n| cfg.nick_candidate(n)).collect();
+        assert_eq!(candidates, vec!["alice", "alicia", "alice_", "alic1", "alic2", "alic3", "alic4"]);
+        assert_eq!(cfg.nick_candidate(1 + 1 + NICK_SUFFIXES), None);
+    }
+
+    #[test]
+    fn keeps_digit_candidates_within_nine_chars() {
+        let short = config("a", &[]);
+        assert_eq!(short.nick_candidate(2), Some("a1".to_string()));
+        let long = config("averylongnickname", &[]);
+        assert_eq!(long.nick_candidate(1), Some("averylongnickname_".to_string()));
+        assert_eq!(long.nick_candidate(2), Some("averylon1".to_string()));
+        assert_eq!(long.nick_candidate(3), Some("averylon2".to_string()));
+    }
+
+    #[test]
+    fn validates_registration_fields() {
+        assert!(config("alice", &[]).validate().is_ok());
+        assert!(config("1alice", &[]).validate().is_err());
+        assert!(config("alice", &["al ice"]).validate().is_err());
+        let mut cfg = config("alice", &[]);
+        cfg.username = "al ice".to_string();
+        assert!(cfg.validate().is_err());
+        cfg.username = "alice@host".to_string();
+        assert!(cfg.validate().is_err());
+        cfg.username = "alice".to_string();
+        cfg.realname = "Alice\r\nQUIT".to_string();
+        assert!(cfg.validate().is_err());
+        cfg.realname = "".to_string();
+        assert!(cfg.validate().is_err());
+        cfg.realname = "Alice Liddell".to_string();
+        cfg.pass = "pass\nword".to_string();
+        assert!(cfg.validate().is_err());
+    }
+}
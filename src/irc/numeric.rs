@@ -0,0 +1,79 @@
+// Not every reply is acted upon, but they are all named for readability.
+#![allow(dead_code)]
+
+pub const RPL_WELCOME : u16 = 1;
+pub const RPL_YOURHOST : u16 = 2;
+pub const RPL_CREATED : u16 = 3;
+pub const RPL_MYINFO : u16 = 4;
+pub const RPL_ISUPPORT : u16 = 5;
+
+pub const RPL_UMODEIS : u16 = 221;
+pub const RPL_AWAY : u16 = 301;
+pub const RPL_ISON : u16 = 303;
+pub const RPL_UNAWAY : u16 = 305;
+pub const RPL_NOWAWAY : u16 = 306;
+pub const RPL_WHOISUSER : u16 = 311;
+pub const RPL_ENDOFWHO : u16 = 315;
+pub const RPL_ENDOFWHOIS : u16 = 318;
+pub const RPL_LISTSTART : u16 = 321;
+pub const RPL_LIST : u16 = 322;
+pub const RPL_LISTEND : u16 = 323;
+pub const RPL_CHANNELMODEIS : u16 = 324;
+pub const RPL_NOTOPIC : u16 = 331;
+pub const RPL_TOPIC : u16 = 332;
+pub const RPL_TOPICWHOTIME : u16 = 333;
+pub const RPL_INVITING : u16 = 341;
+pub const RPL_WHOREPLY : u16 = 352;
+pub const RPL_NAMREPLY : u16 = 353;
+pub const RPL_ENDOFNAMES : u16 = 366;
+pub const RPL_BANLIST : u16 = 367;
+pub const RPL_ENDOFBANLIST : u16 = 368;
+pub const RPL_MOTD : u16 = 372;
+pub const RPL_MOTDSTART : u16 = 375;
+pub const RPL_ENDOFMOTD : u16 = 376;
+pub const RPL_HOSTHIDDEN : u16 = 396;
+
+pub const ERR_NOSUCHNICK : u16 = 401;
+pub const ERR_NOSUCHCHANNEL : u16 = 403;
+pub const ERR_CANNOTSENDTOCHAN : u16 = 404;
+pub const ERR_TOOMANYCHANNELS : u16 = 405;
+pub const ERR_UNKNOWNCOMMAND : u16 = 421;
+pub const ERR_NOMOTD : u16 = 422;
+pub const ERR_NONICKNAMEGIVEN : u16 = 431;
+pub const ERR_ERRONEUSNICKNAME : u16 = 432;
+pub const ERR_NICKNAMEINUSE : u16 = 433;
+pub const ERR_NICKCOLLISION : u16 = 436;
+pub const ERR_UNAVAILRESOURCE : u16 = 437;
+pub const ERR_USERNOTINCHANNEL : u16 = 441;
+pub const ERR_NOTONCHANNEL : u16 = 442;
+pub const ERR_NOTREGISTERED : u16 = 451;
+pub const ERR_NEEDMOREPARAMS : u16 = 461;
+pub const ERR_ALREADYREGISTRED : u16 = 462;
+pub const ERR_PASSWDMISMATCH : u16 = 464;
+pub const ERR_YOUREBANNEDCREEP : u16 = 465;
+pub const ERR_CHANNELISFULL : u16 = 471;
+pub const ERR_UNKNOWNMODE : u16 = 472;
+pub const ERR_INVITEONLYCHAN : u16 = 473;
+pub const ERR_BANNEDFROMCHAN : u16 = 474;
+pub const ERR_BADCHANNELKEY : u16 = 475;
+pub const ERR_NOPRIVILEGES : u16 = 481;
+pub const ERR_CHANOPRIVSNEEDED : u16 = 482;
+
+pub const RPL_MONONLINE : u16 = 730;
+pub const RPL_MONOFFLINE : u16 = 731;
+pub const ERR_MONLISTFULL : u16 = 734;
+
+pub const RPL_LOGGEDIN : u16 = 900;
+pub const RPL_LOGGEDOUT : u16 = 901;
+pub const ERR_NICKLOCKED : u16 = 902;
+pub const RPL_SASLSUCCESS : u16 = 903;
+pub const ERR_SASLFAIL : u16 = 904;
+pub const ERR_SASLTOOLONG : u16 = 905;
+pub const ERR_SASLABORTED : u16 = 906;
+pub const ERR_SASLALREADY : u16 = 907;
+pub const RPL_SASLMECHS : u16 = 908;
+
+/// Error replies occupy the 400-599 range.
+pub fn is_error(code: u16) -> bool {
+    (400..600).contains(&code)
+}
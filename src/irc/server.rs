@@ -1,23 +1,77 @@
 
-use std::io::{self, BufReader, BufRead, Lines, Write};
-use std::iter::FilterMap;
-use std::net::TcpStream;
+use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::ops::{Deref, DerefMut};
 
 use irc::Config;
 use irc::Connection;
-use irc::message::{Message, Hostmask};
+use irc::codec::{Codec, LineReader};
+use irc::stream::Stream;
+use irc::isupport::ISupport;
+use irc::casemap::Casemapping;
+use irc::message::{MessageRef, Hostmask, ModeChange, ModeKind};
 
 pub struct Server {
-    ping_active : Arc<Mutex<bool>>,
-    connected : Arc<Mutex<bool>>,
+    state : Arc<Mutex<ServerState>>,
     userdata : Arc<Mutex<Userdata>>,
     nick : Arc<Mutex<String>>,
     id : usize,
     pub config : Config,
-    stream : TcpStream,
-    channels : Arc<Mutex<Vec<String>>>,
+    stream : Option<Stream>,
+    codec : Codec,
+    channels : Arc<Mutex<Vec<Channel>>>,
+    isupport : Arc<Mutex<ISupport>>,
+    caps : Arc<Mutex<Caps>>,
+}
+
+/// IRCv3 capabilities: what the server offers, with any values such as the
+/// SASL mechanisms, and what we have enabled.
+#[derive(Clone, Debug, Default)]
+pub struct Caps {
+    pub available : HashMap<String, String>,
+    pub enabled : HashSet<String>,
+}
+
+/// Where a network connection is in its life. The bouncer drives the
+/// transitions; workers only see whether the connection is still up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServerState {
+    Connecting,
+    Registering,
+    CapNegotiating,
+    Authenticating,
+    Registered,
+    Disconnected,
+    Backoff,
+}
+
+impl ServerState {
+    /// Whether there is a live connection, registered or not.
+    pub fn is_connected(self) -> bool {
+        !matches!(self, ServerState::Connecting | ServerState::Disconnected | ServerState::Backoff)
+    }
+
+    /// Whether the network is down, so client lines for it can't be delivered.
+    pub fn is_down(self) -> bool {
+        matches!(self, ServerState::Disconnected | ServerState::Backoff)
+    }
+}
+
+/// What we know about a channel we are in: the prefix modes (e.g. "ov") of each
+/// member keyed by casefolded nick, the key and the ban list.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub name : String,
+    pub members : HashMap<String, String>,
+    pub key : Option<String>,
+    pub bans : Vec<String>,
+}
+
+impl Channel {
+    fn new(name: &str) -> Channel {
+        Channel { name: name.to_string(), members: HashMap::new(), key: None, bans: Vec::new() }
+    }
 }
 
 struct Userdata {
@@ -27,23 +81,55 @@ struct Userdata {
 }
 
 impl Server {
-    pub fn connect(id: usize, config: Config) -> io::Result<Server> {
+    /// A network we are not connected to yet.
+    pub fn new(id: usize, config: Config) -> Server {
         let userdata = Userdata { username: "".to_string(), hostname: "".to_string(), realname: "".to_string() };
-        match TcpStream::connect((config.host.as_str(), config.port)) {
-            Ok(sock) => {
-                Ok(Server { id: id, config: config, stream: sock, connected: Arc::new(Mutex::new(true)), userdata: Arc::new(Mutex::new(userdata)), nick: Arc::new(Mutex::new("".to_string())), ping_active: Arc::new(Mutex::new(false)), channels: Arc::new(Mutex::new(Vec::new())) })
-            },
-            Err(err) => Err(err)
+        let codec = Codec::new(config.encoding.as_str(), config.fallback_encoding.as_str()).unwrap_or_default();
+        Server { id: id, config: config, stream: None, codec: codec, state: Arc::new(Mutex::new(ServerState::Disconnected)), userdata: Arc::new(Mutex::new(userdata)), nick: Arc::new(Mutex::new("".to_string())), channels: Arc::new(Mutex::new(Vec::new())), isupport: Arc::new(Mutex::new(ISupport::new())), caps: Arc::new(Mutex::new(Caps::default())) }
+    }
+
+    /// Takes over a freshly opened connection, forgetting everything learned on
+    /// the last one. Workers cloned from the old connection keep its flags, so
+    /// they wind down on their own instead of acting on the new one.
+    pub fn attach(&mut self, config: &Config, stream: Stream) {
+        self.config = config.clone();
+        self.stream = Some(stream);
+        self.state = Arc::new(Mutex::new(ServerState::Registering));
+        self.set_nick("".to_string());
+        self.set_userdata("".to_string(), "".to_string());
+        self.channels.lock().unwrap().deref_mut().clear();
+        *self.isupport.lock().unwrap().deref_mut() = ISupport::new();
+        *self.caps.lock().unwrap().deref_mut() = Caps::default();
+    }
+
+    /// Closes the connection, which ends its read worker.
+    pub fn disconnect(&mut self) {
+        self.set_connected(false);
+        if let Some(ref stream) = self.stream {
+            stream.shutdown();
         }
     }
 
     pub fn try_clone(&self) -> io::Result<Server> {
-        match self.stream.try_clone() {
-            Ok(stream) => Ok(Server { id: self.id, config: self.config.clone(), stream: stream, connected: self.connected.clone(), userdata: self.userdata.clone(), nick: self.nick.clone(), ping_active: self.ping_active.clone(), channels: self.channels.clone() }),
+        match self.stream.as_ref().map(|s| s.try_clone()).transpose() {
+            Ok(stream) => Ok(Server { id: self.id, config: self.config.clone(), stream: stream, codec: self.codec, state: self.state.clone(), userdata: self.userdata.clone(), nick: self.nick.clone(), channels: self.channels.clone(), isupport: self.isupport.clone(), caps: self.caps.clone() }),
             Err(err) => Err(err)
         }
     }
 
+    pub fn state(&self) -> ServerState {
+        *self.state.lock().unwrap().deref()
+    }
+
+    pub fn set_state(&mut self, value: ServerState) {
+        let mut state = self.state.lock().unwrap();
+        *state.deref_mut() = value;
+    }
+
+    pub fn fingerprint(&self) -> Option<String> {
+        self.stream.as_ref().and_then(|s| s.fingerprint())
+    }
+
     pub fn get_nick(&self) -> String {
         self.nick.lock().unwrap().deref().clone()
     }
@@ -65,20 +151,215 @@ impl Server {
         Hostmask::User(nick.deref().clone(), userdata.deref().username.clone(), userdata.deref().hostname.clone())
     }
 
+    /// Runs `f` against the network's ISUPPORT under its lock, without copying it.
+    /// `f` must not call back into this server's ISUPPORT accessors.
+    pub fn with_isupport<R, F: FnOnce(&ISupport) -> R>(&self, f: F) -> R {
+        f(self.isupport.lock().unwrap().deref())
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        self.with_isupport(|isupport| isupport.is_channel(name))
+    }
+
+    pub fn valid_channel(&self, name: &str) -> bool {
+        self.with_isupport(|isupport| isupport.valid_channel(name))
+    }
+
+    pub fn has_isupport(&self, token: &str) -> bool {
+        self.with_isupport(|isupport| isupport.tokens.contains_key(token))
+    }
+
+    pub fn update_isupport(&mut self, tokens: &[String]) {
+        let mut isupport = self.isupport.lock().unwrap();
+        isupport.deref_mut().update(tokens);
+    }
+
+    /// The length of the prefix the server puts on our messages when relaying them.
+    /// Until our user and host are known, assume the longest the server allows.
+    pub fn prefix_len(&self) -> usize {
+        let userdata = self.userdata.lock().unwrap();
+        let nick = self.nick.lock().unwrap();
+        if !userdata.deref().username.is_empty() && !userdata.deref().hostname.is_empty() {
+            return nick.deref().len() + 1 + userdata.deref().username.len() + 1 + userdata.deref().hostname.len();
+        }
+        let isupport = self.isupport.lock().unwrap();
+        let len = |token: &str, default: usize| isupport.deref().tokens.get(token).and_then(|v| v.parse().ok()).unwrap_or(default);
+        // An ident-less user gets a '~' in front of the username.
+        nick.deref().len() + 1 + len("USERLEN", 10) + 1 + 1 + len("HOSTLEN", 63)
+    }
+
+    pub fn caps(&self) -> Caps {
+        self.caps.lock().unwrap().deref().clone()
+    }
+
+    pub fn has_cap(&self, name: &str) -> bool {
+        self.caps.lock().unwrap().deref().enabled.contains(name)
+    }
+
+    /// Records capabilities from CAP LS or CAP NEW, e.g. `sasl=PLAIN,EXTERNAL`.
+    pub fn add_caps(&mut self, list: &str) {
+        let mut caps = self.caps.lock().unwrap();
+        for cap in list.split(' ').filter(|c| !c.is_empty()) {
+            let mut pair = cap.splitn(2, '=');
+            let name = pair.next().unwrap_or("").to_string();
+            caps.deref_mut().available.insert(name, pair.next().unwrap_or("").to_string());
+        }
+    }
+
+    /// Forgets capabilities from CAP DEL; they are no longer enabled either.
+    pub fn remove_caps(&mut self, list: &str) {
+        let mut caps = self.caps.lock().unwrap();
+        for name in list.split(' ').filter(|c| !c.is_empty()) {
+            caps.deref_mut().available.remove(name);
+            caps.deref_mut().enabled.remove(name);
+        }
+    }
+
+    /// Applies a CAP ACK, where a leading '-' means the capability was disabled.
+    pub fn ack_caps(&mut self, list: &str) {
+        let mut caps = self.caps.lock().unwrap();
+        for cap in list.split(' ').filter(|c| !c.is_empty()) {
+            match cap.strip_prefix('-') {
+                Some(name) => caps.deref_mut().enabled.remove(name),
+                None => caps.deref_mut().enabled.insert(cap.to_string()),
+            };
+        }
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        self.isupport.lock().unwrap().deref().casemapping
+    }
+
+    /// Whether a nick is ours under the network's casemapping.
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.casemapping().eq(nick, self.get_nick().as_str())
+    }
+
+    pub fn channels(&self) -> Vec<Channel> {
+        self.channels.lock().unwrap().deref().clone()
+    }
+
     pub fn has_channel(&self, channel: &str) -> bool {
-        let mut channels = self.channels.lock().unwrap();
-        channels.deref().contains(&channel.to_string())
+        let casemapping = self.casemapping();
+        let channels = self.channels.lock().unwrap();
+        channels.deref().iter().any(|c| casemapping.eq(c.name.as_str(), channel))
     }
 
     pub fn add_channel(&mut self, channel: &str) {
-        let mut channels = self.channels.lock().unwrap();
-        channels.deref_mut().push(channel.to_string());
+        if !self.has_channel(channel) {
+            let mut channels = self.channels.lock().unwrap();
+            channels.deref_mut().push(Channel::new(channel));
+        }
     }
 
     pub fn remove_channel(&mut self, channel: &str) {
+        let casemapping = self.casemapping();
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(index) = channels.deref().iter().position(|c| casemapping.eq(c.name.as_str(), channel)) {
+            channels.deref_mut().swap_remove(index);
+        }
+    }
+
+    fn with_channel<F: FnOnce(&mut Channel, Casemapping)>(&mut self, channel: &str, f: F) {
+        let casemapping = self.casemapping();
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(chan) = channels.deref_mut().iter_mut().find(|c| casemapping.eq(c.name.as_str(), channel)) {
+            f(chan, casemapping);
+        }
+    }
+
+    pub fn set_key(&mut self, channel: &str, key: Option<String>) {
+        self.with_channel(channel, |chan, _| chan.key = key);
+    }
+
+    pub fn add_member(&mut self, channel: &str, nick: &str) {
+        self.with_channel(channel, |chan, casemapping| {
+            chan.members.entry(casemapping.fold(nick)).or_default();
+        });
+    }
+
+    pub fn remove_member(&mut self, channel: &str, nick: &str) {
+        self.with_channel(channel, |chan, casemapping| {
+            chan.members.remove(&casemapping.fold(nick));
+        });
+    }
+
+    /// Follows a member's nick change in every channel we share with them,
+    /// returning those channels.
+    pub fn rename_member(&mut self, old: &str, new: &str) -> Vec<String> {
+        let casemapping = self.casemapping();
+        let mut channels = self.channels.lock().unwrap();
+        let mut shared = Vec::new();
+        for chan in channels.deref_mut().iter_mut() {
+            if let Some(modes) = chan.members.remove(&casemapping.fold(old)) {
+                chan.members.insert(casemapping.fold(new), modes);
+                shared.push(chan.name.clone());
+            }
+        }
+        shared
+    }
+
+    /// Drops a member who quit from every channel we share with them, returning
+    /// those channels.
+    pub fn remove_member_everywhere(&mut self, nick: &str) -> Vec<String> {
+        let casemapping = self.casemapping();
         let mut channels = self.channels.lock().unwrap();
-        let index = channels.deref().iter().position(|ref r| *r == channel).unwrap();
-        channels.deref_mut().swap_remove(index);
+        let mut shared = Vec::new();
+        for chan in channels.deref_mut().iter_mut() {
+            if chan.members.remove(&casemapping.fold(nick)).is_some() {
+                shared.push(chan.name.clone());
+            }
+        }
+        shared
+    }
+
+    /// Records the members listed in an RPL_NAMREPLY, with their status symbols.
+    pub fn add_names(&mut self, channel: &str, names: &str) {
+        // with_channel takes the ISUPPORT lock itself, so the names are read before it.
+        let members : Vec<(&str, String)> = self.with_isupport(|isupport| {
+            names.split(' ').filter(|n| !n.is_empty()).map(|name| {
+                let nick = name.trim_start_matches(|c| isupport.prefix_mode(c).is_some());
+                let modes = name[..name.len() - nick.len()].chars().filter_map(|c| isupport.prefix_mode(c)).collect();
+                (nick, modes)
+            }).collect()
+        });
+        self.with_channel(channel, |chan, casemapping| {
+            for (nick, modes) in members {
+                chan.members.insert(casemapping.fold(nick), modes);
+            }
+        });
+    }
+
+    /// Applies parsed channel mode changes to member status, the key and the ban list.
+    pub fn apply_modes(&mut self, channel: &str, changes: &[ModeChange]) {
+        self.with_channel(channel, |chan, casemapping| {
+            for change in changes {
+                match (change.kind, change.mode, change.param.as_ref()) {
+                    (ModeKind::Prefix, mode, Some(nick)) => {
+                        if let Some(modes) = chan.members.get_mut(&casemapping.fold(nick)) {
+                            if change.adding && !modes.contains(mode) {
+                                modes.push(mode);
+                            } else if !change.adding {
+                                modes.retain(|m| m != mode);
+                            }
+                        }
+                    }
+                    (ModeKind::List, 'b', Some(mask)) => {
+                        if change.adding {
+                            if !chan.bans.contains(mask) {
+                                chan.bans.push(mask.clone());
+                            }
+                        } else {
+                            chan.bans.retain(|b| !casemapping.eq(b, mask));
+                        }
+                    }
+                    (_, 'k', key) => {
+                        chan.key = if change.adding { key.cloned() } else { None };
+                    }
+                    _ => ()
+                }
+            }
+        });
     }
 }
 
@@ -92,43 +373,48 @@ impl Connection for Server {
     }
 
     fn is_connected(&self) -> bool {
-        *self.connected.lock().unwrap().deref()
+        self.state().is_connected()
     }
 
-    fn set_connected(&mut self, value: bool) {
-        let mut connected = self.connected.lock().unwrap();
-        *connected.deref_mut() = value;
-    }
-
-    fn try_ping(&mut self) -> bool {
-        let mut ping_active = self.ping_active.clone();
-        if *ping_active.lock().unwrap().deref() {
-            self.set_connected(false);
-            false
-        } else {
-            let string = Message::ping(Hostmask::None, ":carbon").to_string();
-            println!("[{}] <= {}", self.name(), string);
-            *ping_active.lock().unwrap().deref_mut() = true;
-            self.send(string);
-            true
+    /// Leaves out the SASL payload, the server password and what we tell
+    /// services, as those carry credentials.
+    fn loggable(&self, line: &str) -> String {
+        let msg = MessageRef::parse(line);
+        let service = self.config.nickserv.as_ref().map_or("NickServ", |ns| ns.service.as_str());
+        let mechanism = self.config.sasl.as_ref().map(|sasl| sasl.mechanism.to_uppercase());
+        match &*msg.verb() {
+            "AUTHENTICATE" if msg.param(0) == "+" || mechanism.is_some_and(|m| m == msg.param(0)) => line.to_string(),
+            "AUTHENTICATE" | "PASS" => format!("{} <redacted>", msg.verb()),
+            "PRIVMSG" if self.casemapping().eq(msg.param(0), service) => {
+                let command = msg.param(1).split(' ').next().unwrap_or("");
+                format!("PRIVMSG {} :{} <redacted>", msg.param(0), command)
+            }
+            _ => line.to_string()
         }
     }
 
-    fn register_pong(&mut self) {
-        let mut ping_active = self.ping_active.lock().unwrap();
-        *ping_active.deref_mut() = false;
+    fn set_connected(&mut self, value: bool) {
+        match (value, self.state().is_connected()) {
+            (false, true) => self.set_state(ServerState::Disconnected),
+            (true, false) => self.set_state(ServerState::Registering),
+            _ => ()
+        }
     }
 
     fn send(&mut self, string: String) -> io::Result<()> {
-        let clone = self.try_clone();
-        let newline = |mut this: Server| this.stream.write_all(b"\r\n");
-        self.stream.write_all(string.as_bytes()).and(clone).and_then(newline)
+        let mut line = self.codec.encode(string.as_str());
+        line.extend_from_slice(b"\r\n");
+        match self.stream {
+            Some(ref mut stream) => stream.write_all(&line),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected"))
+        }
     }
 
-    fn read(&mut self) -> io::Result<FilterMap<Lines<BufReader<TcpStream>>, fn(io::Result<String>) -> Option<String>>> {
-        match self.stream.try_clone() {
-            Ok(stream) => Ok(BufReader::new(stream).lines().filter_map(Result::ok)),
-            Err(err) => Err(err)
+    fn read(&mut self) -> io::Result<LineReader<Stream>> {
+        match self.stream.as_ref().map(|s| s.try_clone()) {
+            Some(Ok(stream)) => Ok(LineReader::new(stream, self.codec)),
+            Some(Err(err)) => Err(err),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected"))
         }
     }
 }
@@ -0,0 +1,381 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libc;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::{X509, X509NameBuilder};
+
+use irc::Config;
+
+// The longest a TLS reader holds the session waiting for the rest of a record.
+const TLS_READ_TIMEOUT : u64 = 100;
+// How long a peer gets to finish its TLS handshake, in seconds.
+const HANDSHAKE_TIMEOUT : u64 = 30;
+// How long each address of a server gets to accept the connection, in seconds.
+const CONNECT_TIMEOUT : u64 = 30;
+
+/// A connected TCP or Unix domain socket.
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn try_clone(&self) -> io::Result<Socket> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.try_clone().map(Socket::Tcp),
+            Socket::Unix(ref sock) => sock.try_clone().map(Socket::Unix),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match *self {
+            Socket::Tcp(ref sock) => sock.shutdown(Shutdown::Both),
+            Socket::Unix(ref sock) => sock.shutdown(Shutdown::Both),
+        };
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.set_read_timeout(timeout),
+            Socket::Unix(ref sock) => sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// The peer's address, e.g. `127.0.0.1:4242`. Unix peers are all local.
+    pub fn peer_name(&self) -> String {
+        match *self {
+            Socket::Tcp(ref sock) => sock.peer_addr().map(|a| a.to_string()).unwrap_or_default(),
+            Socket::Unix(_) => "localhost".to_string(),
+        }
+    }
+
+    /// The peer's host without the port, as used in its hostmask.
+    pub fn peer_host(&self) -> String {
+        match *self {
+            Socket::Tcp(ref sock) => sock.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default(),
+            Socket::Unix(_) => "localhost".to_string(),
+        }
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Socket::Tcp(ref sock) => sock.as_raw_fd(),
+            Socket::Unix(ref sock) => sock.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.read(buf),
+            Socket::Unix(ref mut sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.write(buf),
+            Socket::Unix(ref mut sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.flush(),
+            Socket::Unix(ref mut sock) => sock.flush(),
+        }
+    }
+}
+
+/// A bound TCP or Unix domain listening socket.
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl ListenSocket {
+    pub fn bind_tcp(address: &str) -> io::Result<ListenSocket> {
+        TcpListener::bind(address).map(ListenSocket::Tcp)
+    }
+
+    /// Binds a Unix domain socket, replacing a stale socket left by an earlier
+    /// run, and applies `mode` to it when given. Anything else at the path is
+    /// left alone.
+    pub fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<ListenSocket> {
+        match fs::symlink_metadata(path) {
+            Ok(ref meta) if !meta.file_type().is_socket() => {
+                let text = format!("{} exists and is not a socket", path);
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, text));
+            }
+            Ok(_) if UnixStream::connect(path).is_err() => fs::remove_file(path)?,
+            _ => ()
+        }
+        let listener = match mode {
+            Some(mode) => {
+                // Bind owner-only so the socket is never reachable with looser
+                // permissions than asked for, then widen it to `mode`.
+                let umask = unsafe { libc::umask(0o177) };
+                let listener = UnixListener::bind(path);
+                unsafe { libc::umask(umask) };
+                let listener = listener?;
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                listener
+            }
+            None => UnixListener::bind(path)?
+        };
+        Ok(ListenSocket::Unix(listener))
+    }
+
+    pub fn accept(&self) -> io::Result<Socket> {
+        match *self {
+            ListenSocket::Tcp(ref listener) => listener.accept().map(|(sock, _)| Socket::Tcp(sock)),
+            ListenSocket::Unix(ref listener) => listener.accept().map(|(sock, _)| Socket::Unix(sock)),
+        }
+    }
+}
+
+/// A connection that is either plain or TLS. Clones share the underlying
+/// socket, so one thread can read while another writes.
+#[derive(Debug)]
+pub enum Stream {
+    Plain(Socket),
+    Tls(Arc<Mutex<SslStream<Socket>>>),
+}
+
+impl Stream {
+    /// Opens a TCP connection to a network, negotiating TLS when `ssl` is set.
+    ///
+    /// The server's certificate is checked against the system CA bundle (or
+    /// `ca_file`) and the host name, which is also sent as SNI. Pinned
+    /// fingerprints replace that check, and trust-on-first-use accepts any
+    /// certificate until one is pinned. `verify: false` accepts anything.
+    pub fn connect(config: &Config) -> io::Result<Stream> {
+        let host = config.host.as_str();
+        let sock = Socket::Tcp(connect_tcp(host, config.port)?);
+        if !config.ssl {
+            return Ok(Stream::Plain(sock));
+        }
+        let pinning = !config.fingerprints.is_empty() || config.tofu;
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(other)?;
+        if let Some(ref path) = config.ca_file {
+            builder.set_ca_file(path).map_err(other)?;
+        }
+        // The key may live alongside the certificate in the same PEM file.
+        if let Some(ref cert) = config.client_cert {
+            let key = config.client_key.as_ref().unwrap_or(cert);
+            builder.set_certificate_chain_file(cert).map_err(other)?;
+            builder.set_private_key_file(key, SslFiletype::PEM).map_err(other)?;
+            builder.check_private_key().map_err(other)?;
+        }
+        if !config.verify || pinning {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        let connector = builder.build().configure().map_err(other)?
+            .verify_hostname(config.verify && !pinning);
+        sock.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+        let tls = connector.connect(host, sock).map_err(other)?;
+        if config.verify && !config.fingerprints.is_empty() {
+            let fingerprint = fingerprint(&tls)?;
+            if !config.fingerprints.iter().any(|f| normalize_fingerprint(f) == normalize_fingerprint(&fingerprint)) {
+                let text = format!("certificate fingerprint {} does not match any pinned fingerprint", fingerprint);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, text));
+            }
+        }
+        Stream::shared(tls)
+    }
+
+    /// Completes the server side of a TLS handshake with an accepted client.
+    pub fn accept(acceptor: &SslAcceptor, sock: Socket) -> io::Result<Stream> {
+        sock.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+        let tls = acceptor.accept(sock).map_err(other)?;
+        Stream::shared(tls)
+    }
+
+    fn shared(tls: SslStream<Socket>) -> io::Result<Stream> {
+        // A TLS session can't be split into halves, so readers wait for data
+        // without it and only take it to read. The timeout bounds how long a
+        // half-arrived record can keep writers out.
+        tls.get_ref().set_read_timeout(Some(Duration::from_millis(TLS_READ_TIMEOUT)))?;
+        Ok(Stream::Tls(Arc::new(Mutex::new(tls))))
+    }
+
+    /// Closes both directions, waking up any thread blocked reading.
+    pub fn shutdown(&self) {
+        match *self {
+            Stream::Plain(ref sock) => sock.shutdown(),
+            Stream::Tls(ref tls) => tls.lock().unwrap().get_ref().shutdown(),
+        }
+    }
+
+    pub fn peer_name(&self) -> String {
+        match *self {
+            Stream::Plain(ref sock) => sock.peer_name(),
+            Stream::Tls(ref tls) => tls.lock().unwrap().get_ref().peer_name(),
+        }
+    }
+
+    pub fn peer_host(&self) -> String {
+        match *self {
+            Stream::Plain(ref sock) => sock.peer_host(),
+            Stream::Tls(ref tls) => tls.lock().unwrap().get_ref().peer_host(),
+        }
+    }
+
+    /// The SHA-256 fingerprint of the peer's certificate, for TLS connections.
+    pub fn fingerprint(&self) -> Option<String> {
+        match *self {
+            Stream::Plain(_) => None,
+            Stream::Tls(ref tls) => fingerprint(&tls.lock().unwrap()).ok(),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Plain(ref sock) => sock.try_clone().map(Stream::Plain),
+            Stream::Tls(ref tls) => Ok(Stream::Tls(tls.clone())),
+        }
+    }
+}
+
+/// Builds the acceptor for TLS client connections from PEM files. With
+/// `generate` set, a self-signed certificate is created first if either file
+/// is missing.
+pub fn acceptor(cert: &str, key: &str, generate: bool) -> io::Result<SslAcceptor> {
+    if generate && !(Path::new(cert).exists() && Path::new(key).exists()) {
+        println!("Generating self-signed certificate {}", cert);
+        let (cert_pem, key_pem) = self_signed().map_err(other)?;
+        OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(key)?.write_all(&key_pem)?;
+        File::create(cert)?.write_all(&cert_pem)?;
+    }
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(other)?;
+    builder.set_private_key_file(key, SslFiletype::PEM).map_err(other)?;
+    builder.set_certificate_chain_file(cert).map_err(other)?;
+    builder.check_private_key().map_err(other)?;
+    Ok(builder.build())
+}
+
+/// A fresh RSA key and a ten year certificate for it, both as PEM.
+fn self_signed() -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "carbon")?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(3650)?;
+
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    cert.sign(&key, MessageDigest::sha256())?;
+    Ok((cert.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+}
+
+/// Formats like `openssl x509 -fingerprint`, e.g. `AB:CD:...`.
+fn fingerprint(tls: &SslStream<Socket>) -> io::Result<String> {
+    let cert = tls.ssl().peer_certificate().ok_or_else(|| other("server sent no certificate"))?;
+    let digest = cert.digest(MessageDigest::sha256()).map_err(other)?;
+    let hex : Vec<String> = digest.iter().map(|b| format!("{:02X}", b)).collect();
+    Ok(hex.join(":"))
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
+}
+
+/// Tries each address the host resolves to in turn, so that one that drops
+/// our packets can't hold up the others.
+fn connect_tcp(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", host));
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT)) {
+            Ok(sock) => return Ok(sock),
+            Err(err) => last = err,
+        }
+    }
+    Err(last)
+}
+
+/// Blocks until a socket has something to read, or has been closed.
+fn wait_readable(fd: RawFd) -> io::Result<()> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    loop {
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut sock) => sock.read(buf),
+            Stream::Tls(ref tls) => loop {
+                // Data OpenSSL has already decrypted won't show up on the socket.
+                let fd = {
+                    let tls = tls.lock().unwrap();
+                    if tls.ssl().pending() == 0 { Some(tls.get_ref().as_raw_fd()) } else { None }
+                };
+                if let Some(fd) = fd {
+                    wait_readable(fd)?;
+                }
+                match tls.lock().unwrap().read(buf) {
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => (),
+                    result => return result,
+                }
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut sock) => sock.write(buf),
+            Stream::Tls(ref tls) => tls.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut sock) => sock.flush(),
+            Stream::Tls(ref tls) => tls.lock().unwrap().flush(),
+        }
+    }
+}
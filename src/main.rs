@@ -1,5 +1,9 @@
 extern crate yaml_rust;
 extern crate rand;
+extern crate chrono;
+extern crate encoding_rs;
+extern crate openssl;
+extern crate libc;
 
 use std::env;
 use std::fs::File;
@@ -19,6 +23,8 @@ fn main() {
     f.read_to_string(&mut s).unwrap();
 
     let mut cfgs = Vec::new();
+    let mut tls = None;
+    let mut listens = Vec::new();
 
     let docs = YamlLoader::load_from_str(s.as_str()).unwrap();
 
@@ -30,12 +36,115 @@ fn main() {
             let mut rng = rand::thread_rng();
             for (k, v) in h {
                 match *k {
+                    yaml::Yaml::String(ref name) if name == "listen" => {
+                        match *v {
+                            yaml::Yaml::Array(ref a) => {
+                                for x in a {
+                                    match *x {
+                                        yaml::Yaml::String(ref s) => {
+                                            listens.push(bouncer::ListenConfig { addr: bouncer::ListenAddr::Tcp(s.clone()), tls: false });
+                                        }
+                                        yaml::Yaml::Hash(ref h) => {
+                                            let mut address = None;
+                                            let mut path = None;
+                                            let mut mode = None;
+                                            let mut listen_tls = false;
+                                            for (k, v) in h {
+                                                match *k {
+                                                    yaml::Yaml::String(ref key) => {
+                                                        match (key.as_str(), v) {
+                                                            ("address", yaml::Yaml::String(s)) => {
+                                                                address = Some(s.clone());
+                                                            }
+                                                            ("path", yaml::Yaml::String(s)) => {
+                                                                path = Some(s.clone());
+                                                            }
+                                                            // Permissions are octal whether written as 660 or "0660".
+                                                            ("mode", yaml::Yaml::Integer(i)) => {
+                                                                mode = u32::from_str_radix(i.to_string().as_str(), 8).ok();
+                                                            }
+                                                            ("mode", yaml::Yaml::String(s)) => {
+                                                                mode = u32::from_str_radix(s.as_str(), 8).ok();
+                                                            }
+                                                            ("tls", yaml::Yaml::Boolean(b)) => {
+                                                                listen_tls = *b;
+                                                            }
+                                                            _ => println!("Malformed config file: Unexpected or malformed listen parameter {}", key)
+                                                        }
+                                                    }
+                                                    _ => println!("Malformed config file: Expected string in listen parameters")
+                                                }
+                                            }
+                                            match (address, path) {
+                                                (Some(address), None) => {
+                                                    listens.push(bouncer::ListenConfig { addr: bouncer::ListenAddr::Tcp(address), tls: listen_tls });
+                                                }
+                                                (None, Some(path)) => {
+                                                    listens.push(bouncer::ListenConfig { addr: bouncer::ListenAddr::Unix(path, mode), tls: listen_tls });
+                                                }
+                                                _ => println!("Malformed config file: Expected either address or path for listener")
+                                            }
+                                        }
+                                        _ => println!("Malformed config file: Expected address string or hash in listen list")
+                                    }
+                                }
+                            }
+                            _ => println!("Malformed config file: Expected array of listeners")
+                        }
+                    }
+                    yaml::Yaml::String(ref name) if name == "tls" => {
+                        let mut cert = "carbon.pem".to_string();
+                        let mut key = "carbon.key".to_string();
+                        let mut generate = false;
+                        match *v {
+                            yaml::Yaml::Hash(ref h) => {
+                                for (k, v) in h {
+                                    match *k {
+                                        yaml::Yaml::String(ref key_name) => {
+                                            match (key_name.as_ref(), v) {
+                                                ("cert", yaml::Yaml::String(s)) => {
+                                                    cert = s.clone();
+                                                }
+                                                ("key", yaml::Yaml::String(s)) => {
+                                                    key = s.clone();
+                                                }
+                                                ("generate", yaml::Yaml::Boolean(b)) => {
+                                                    generate = *b;
+                                                }
+                                                ("cert", _) | ("key", _) => println!("Malformed config file: Expected string for {}", key_name),
+                                                ("generate", _) => println!("Malformed config file: Expected boolean for generate"),
+                                                _ => println!("Malformed config file: Unexpected tls parameter")
+                                            }
+                                        }
+                                        _ => println!("Malformed config file: Expected string in tls parameters")
+                                    }
+                                }
+                            }
+                            _ => println!("Malformed config file: Expected hash of tls parameters")
+                        }
+                        tls = Some(bouncer::TlsConfig { cert: cert, key: key, generate: generate });
+                    }
                     yaml::Yaml::String(ref name) => {
                         let mut nick = format!("carbon{}", rng.gen::<u16>());
+                        let mut alt_nicks = Vec::new();
                         let mut host = String::new();
                         let mut port = 6667;
                         let mut pass = String::new();
+                        let mut username = String::new();
+                        let mut realname = "carbon".to_string();
+                        let mut sasl = None;
+                        let mut nickserv = None;
                         let mut ssl = false;
+                        let mut ca_file = None;
+                        let mut client_cert = None;
+                        let mut client_key = None;
+                        let mut fingerprints = Vec::new();
+                        let mut tofu = false;
+                        let mut verify = true;
+                        let mut ctcp_replies = true;
+                        let mut caps = vec!["multi-prefix".to_string(), "server-time".to_string(), "message-tags".to_string(), "cap-notify".to_string()];
+                        let mut encoding = "utf-8".to_string();
+                        let mut fallback_encoding = "windows-1252".to_string();
                         let mut chans = Vec::new();
                         match *v {
                             yaml::Yaml::Hash(ref h) => {
@@ -51,6 +160,37 @@ fn main() {
                                                         _ => println!("Malformed config file: Expected string for nick")
                                                     }
                                                 }
+                                                "username" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) => {
+                                                            username = s.clone();
+                                                        }
+                                                        _ => println!("Malformed config file: Expected string for username")
+                                                    }
+                                                }
+                                                "realname" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) => {
+                                                            realname = s.clone();
+                                                        }
+                                                        _ => println!("Malformed config file: Expected string for realname")
+                                                    }
+                                                }
+                                                "alt_nicks" => {
+                                                    match *v {
+                                                        yaml::Yaml::Array(ref a) => {
+                                                            for x in a {
+                                                                match *x {
+                                                                    yaml::Yaml::String(ref s) => {
+                                                                        alt_nicks.push(s.clone());
+                                                                    }
+                                                                    _ => println!("Malformed config file: Expected string in alt_nicks")
+                                                                }
+                                                            }
+                                                        }
+                                                        _ => println!("Malformed config file: Expected array of alt_nicks")
+                                                    }
+                                                }
                                                 "host" => {
                                                     match *v {
                                                         yaml::Yaml::String(ref s) => {
@@ -75,6 +215,85 @@ fn main() {
                                                         _ => println!("Malformed config file: Expected string for pass")
                                                     }
                                                 }
+                                                "sasl" => {
+                                                    let mut mechanism = "plain".to_string();
+                                                    let mut user = String::new();
+                                                    let mut password = String::new();
+                                                    let mut required = false;
+                                                    match *v {
+                                                        yaml::Yaml::Hash(ref h) => {
+                                                            for (k, v) in h {
+                                                                match *k {
+                                                                    yaml::Yaml::String(ref key) => {
+                                                                        match (key.as_str(), v) {
+                                                                            ("mechanism", yaml::Yaml::String(s)) if s.to_lowercase() == "plain" || s.to_lowercase() == "external" => {
+                                                                                mechanism = s.to_lowercase();
+                                                                            }
+                                                                            ("user", yaml::Yaml::String(s)) => {
+                                                                                user = s.clone();
+                                                                            }
+                                                                            ("password", yaml::Yaml::String(s)) => {
+                                                                                password = s.clone();
+                                                                            }
+                                                                            ("required", yaml::Yaml::Boolean(b)) => {
+                                                                                required = *b;
+                                                                            }
+                                                                            ("mechanism", _) => println!("Malformed config file: Expected plain or external as sasl mechanism"),
+                                                                            _ => println!("Malformed config file: Unexpected or malformed sasl parameter {}", key)
+                                                                        }
+                                                                    }
+                                                                    _ => println!("Malformed config file: Expected string in sasl parameters")
+                                                                }
+                                                            }
+                                                        }
+                                                        _ => println!("Malformed config file: Expected hash of sasl parameters")
+                                                    }
+                                                    sasl = Some(irc::Sasl { mechanism: mechanism, user: user, password: password, required: required });
+                                                }
+                                                "nickserv" => {
+                                                    let mut ns = irc::NickServ {
+                                                        service: "NickServ".to_string(),
+                                                        account: String::new(),
+                                                        password: String::new(),
+                                                        identify: "IDENTIFY {account} {password}".to_string(),
+                                                        ghost: "GHOST {nick} {password}".to_string(),
+                                                        delay_join: false,
+                                                    };
+                                                    match *v {
+                                                        yaml::Yaml::Hash(ref h) => {
+                                                            for (k, v) in h {
+                                                                match *k {
+                                                                    yaml::Yaml::String(ref key) => {
+                                                                        match (key.as_str(), v) {
+                                                                            ("service", yaml::Yaml::String(s)) => {
+                                                                                ns.service = s.clone();
+                                                                            }
+                                                                            ("account", yaml::Yaml::String(s)) => {
+                                                                                ns.account = s.clone();
+                                                                            }
+                                                                            ("password", yaml::Yaml::String(s)) => {
+                                                                                ns.password = s.clone();
+                                                                            }
+                                                                            ("identify", yaml::Yaml::String(s)) => {
+                                                                                ns.identify = s.clone();
+                                                                            }
+                                                                            ("ghost", yaml::Yaml::String(s)) => {
+                                                                                ns.ghost = s.clone();
+                                                                            }
+                                                                            ("delay_join", yaml::Yaml::Boolean(b)) => {
+                                                                                ns.delay_join = *b;
+                                                                            }
+                                                                            _ => println!("Malformed config file: Unexpected or malformed nickserv parameter {}", key)
+                                                                        }
+                                                                    }
+                                                                    _ => println!("Malformed config file: Expected string in nickserv parameters")
+                                                                }
+                                                            }
+                                                        }
+                                                        _ => println!("Malformed config file: Expected hash of nickserv parameters")
+                                                    }
+                                                    nickserv = Some(ns);
+                                                }
                                                 "ssl" => {
                                                     match *v {
                                                         yaml::Yaml::Boolean(ref b) => {
@@ -83,6 +302,100 @@ fn main() {
                                                         _ => println!("Malformed config file: Expected boolean for ssl")
                                                     }
                                                 }
+                                                "ca_file" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) => {
+                                                            ca_file = Some(s.clone());
+                                                        }
+                                                        _ => println!("Malformed config file: Expected string for ca_file")
+                                                    }
+                                                }
+                                                "client_cert" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) => {
+                                                            client_cert = Some(s.clone());
+                                                        }
+                                                        _ => println!("Malformed config file: Expected string for client_cert")
+                                                    }
+                                                }
+                                                "client_key" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) => {
+                                                            client_key = Some(s.clone());
+                                                        }
+                                                        _ => println!("Malformed config file: Expected string for client_key")
+                                                    }
+                                                }
+                                                "fingerprints" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) => {
+                                                            fingerprints.push(s.clone());
+                                                        }
+                                                        yaml::Yaml::Array(ref a) => {
+                                                            for x in a {
+                                                                match *x {
+                                                                    yaml::Yaml::String(ref s) => {
+                                                                        fingerprints.push(s.clone());
+                                                                    }
+                                                                    _ => println!("Malformed config file: Expected string in fingerprint list")
+                                                                }
+                                                            }
+                                                        }
+                                                        _ => println!("Malformed config file: Expected string or array of fingerprints")
+                                                    }
+                                                }
+                                                "tofu" => {
+                                                    match *v {
+                                                        yaml::Yaml::Boolean(ref b) => {
+                                                            tofu = *b;
+                                                        }
+                                                        _ => println!("Malformed config file: Expected boolean for tofu")
+                                                    }
+                                                }
+                                                "verify" => {
+                                                    match *v {
+                                                        yaml::Yaml::Boolean(ref b) => {
+                                                            verify = *b;
+                                                        }
+                                                        _ => println!("Malformed config file: Expected boolean for verify")
+                                                    }
+                                                }
+                                                "caps" => {
+                                                    match *v {
+                                                        yaml::Yaml::Array(ref a) => {
+                                                            caps.clear();
+                                                            for x in a {
+                                                                match *x {
+                                                                    yaml::Yaml::String(ref s) => {
+                                                                        caps.push(s.clone());
+                                                                    }
+                                                                    _ => println!("Malformed config file: Expected string in caps")
+                                                                }
+                                                            }
+                                                        }
+                                                        _ => println!("Malformed config file: Expected array of caps")
+                                                    }
+                                                }
+                                                "ctcp_replies" => {
+                                                    match *v {
+                                                        yaml::Yaml::Boolean(ref b) => {
+                                                            ctcp_replies = *b;
+                                                        }
+                                                        _ => println!("Malformed config file: Expected boolean for ctcp_replies")
+                                                    }
+                                                }
+                                                "encoding" | "fallback_encoding" => {
+                                                    match *v {
+                                                        yaml::Yaml::String(ref s) if irc::codec::Codec::is_known(s) => {
+                                                            if key == "encoding" {
+                                                                encoding = s.clone();
+                                                            } else {
+                                                                fallback_encoding = s.clone();
+                                                            }
+                                                        }
+                                                        _ => println!("Malformed config file: Expected known encoding name for {}", key)
+                                                    }
+                                                }
                                                 "chans" => {
                                                     match *v {
                                                         yaml::Yaml::Array(ref a) => {
@@ -108,8 +421,20 @@ fn main() {
                             _ => println!("Malformed config file: Expected hash of server parameters")
                         }
                         if !host.is_empty() {
-                            let cfg = irc::Config {name: name.clone(), nick: nick, host: host, port: port, pass: pass, ssl: ssl, chans: chans};
-                            cfgs.push(cfg);
+                            // The username and account default to the nick, which may come later in the file.
+                            if username.is_empty() {
+                                username = nick.clone();
+                            }
+                            if let Some(ref mut ns) = nickserv {
+                                if ns.account.is_empty() {
+                                    ns.account = nick.clone();
+                                }
+                            }
+                            let cfg = irc::Config {name: name.clone(), nick: nick, alt_nicks: alt_nicks, username: username, realname: realname, host: host, port: port, pass: pass, sasl: sasl, nickserv: nickserv, ssl: ssl, ca_file: ca_file, client_cert: client_cert, client_key: client_key, fingerprints: fingerprints, tofu: tofu, verify: verify, ctcp_replies: ctcp_replies, caps: caps, encoding: encoding, fallback_encoding: fallback_encoding, chans: chans};
+                            match cfg.validate() {
+                                Ok(()) => cfgs.push(cfg),
+                                Err(err) => println!("Malformed config file: Skipping {}: {}", name, err)
+                            }
                         }
                     }
                     _ => println!("Malformed config file: Expected server name string")
@@ -121,7 +446,9 @@ fn main() {
         }
     }
 
-    let bnc = bouncer::Bouncer::new(cfgs).unwrap();
+    // Certificates pinned on first use are remembered next to the config.
+    let pinfile = format!("{}.pins", conffile);
+    let bnc = bouncer::Bouncer::new(cfgs, listens, tls, pinfile).unwrap();
     bnc.run();
 
     // Chained key/array access is checked and won't panic,